@@ -0,0 +1,201 @@
+//! Writing a tree out as a tar archive (POSIX ustar), like `git archive --format=tar`.
+use std::io::Write;
+
+use anyhow::Context;
+
+use crate::{
+    store::{read_blob, read_object},
+    tree::{GitObject, Mode, ObjType},
+};
+
+const BLOCK: usize = 512;
+
+/// Write every file under the tree `tree_sha` to `out` as a tar archive, each path starting
+/// with `prefix` (empty or a directory ending in `/`) and dated `mtime` (seconds since the
+/// epoch).
+///
+/// Directories get their own entries and gitlinks become empty directories like git does.
+pub fn write_tar<W: Write>(
+    tree_sha: &str,
+    prefix: &str,
+    mtime: u64,
+    out: &mut W,
+) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        prefix.is_empty() || prefix.ends_with('/'),
+        "prefix '{}' must end with '/'",
+        prefix
+    );
+    if !prefix.is_empty() {
+        write_header(out, prefix, b'5', 0o755, 0, mtime, "")?;
+    }
+    write_tree_entries(tree_sha, prefix, mtime, out)?;
+    // The end of the archive is two empty blocks
+    out.write_all(&[0; BLOCK * 2])?;
+    Ok(())
+}
+
+fn write_tree_entries<W: Write>(
+    tree_sha: &str,
+    prefix: &str,
+    mtime: u64,
+    out: &mut W,
+) -> anyhow::Result<()> {
+    let ObjType::Tree { objs, .. } = GitObject::try_from_bytes(&read_object(tree_sha)?)?.obj_type
+    else {
+        anyhow::bail!("object {} is not a tree", tree_sha)
+    };
+    for o in objs {
+        let path = format!("{}{}", prefix, o.as_path_str());
+        let sha = hex::encode(o.sha.as_ref().expect("tree entries have a sha"));
+        match o.mode {
+            Mode::SubDir => {
+                let dir = format!("{}/", path);
+                write_header(out, &dir, b'5', 0o755, 0, mtime, "")?;
+                write_tree_entries(&sha, &dir, mtime, out)?;
+            }
+            Mode::SubMod => write_header(out, &format!("{}/", path), b'5', 0o755, 0, mtime, "")?,
+            Mode::SymLink => {
+                let target = read_blob(&sha)?;
+                let target = String::from_utf8(target).context("symlink target isn't UTF-8")?;
+                write_header(out, &path, b'2', 0o777, 0, mtime, &target)?;
+            }
+            Mode::FileBlob | Mode::ExeBlob => {
+                let content = read_blob(&sha)?;
+                let perm = if let Mode::ExeBlob = o.mode {
+                    0o755
+                } else {
+                    0o644
+                };
+                write_header(out, &path, b'0', perm, content.len() as u64, mtime, "")?;
+                out.write_all(&content)?;
+                // File data is padded with zeros to a whole block
+                let pad = (BLOCK - content.len() % BLOCK) % BLOCK;
+                out.write_all(&vec![0; pad])?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Write a ustar header block, paths too long for the name field are split into the prefix
+/// field at a `/`.
+fn write_header<W: Write>(
+    out: &mut W,
+    path: &str,
+    kind: u8,
+    mode: u32,
+    size: u64,
+    mtime: u64,
+    link: &str,
+) -> anyhow::Result<()> {
+    let (prefix, name) = if path.len() <= 100 {
+        ("", path)
+    } else {
+        // The `/` between the two is implied, so it can't land in either field
+        path.trim_end_matches('/')
+            .char_indices()
+            .filter(|(at, ch)| *ch == '/' && *at <= 155 && path.len() - at - 1 <= 100)
+            .map(|(at, _)| (&path[..at], &path[at + 1..]))
+            .next()
+            .with_context(|| format!("path '{}' is too long for a tar archive", path))?
+    };
+    anyhow::ensure!(link.len() <= 100, "symlink target '{}' is too long", link);
+
+    let mut header = [0u8; BLOCK];
+    let mut field = |at: usize, value: &[u8]| header[at..at + value.len()].copy_from_slice(value);
+    field(0, name.as_bytes());
+    field(100, format!("{:07o}\0", mode).as_bytes());
+    field(108, b"0000000\0");
+    field(116, b"0000000\0");
+    field(124, format!("{:011o}\0", size).as_bytes());
+    field(136, format!("{:011o}\0", mtime).as_bytes());
+    field(156, &[kind]);
+    field(157, link.as_bytes());
+    field(257, b"ustar\0");
+    field(263, b"00");
+    field(265, b"root");
+    field(297, b"root");
+    field(345, prefix.as_bytes());
+    // The checksum is summed with its own field as spaces
+    header[148..156].fill(b' ');
+    let sum = header.iter().map(|b| u32::from(*b)).sum::<u32>();
+    header[148..156].copy_from_slice(format!("{:06o}\0 ", sum).as_bytes());
+    out.write_all(&header)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{blob, temp_repo, tree};
+
+    /// The `(path, kind, mode, size)` of each ustar entry in `tar`, checking each header's
+    /// checksum and that the archive ends with two empty blocks.
+    fn entries(tar: &[u8]) -> Vec<(String, u8, u32, usize)> {
+        let octal = |field: &[u8]| {
+            let text = std::str::from_utf8(field).unwrap();
+            usize::from_str_radix(text.trim_end_matches(['\0', ' ']), 8).unwrap()
+        };
+        let text = |field: &[u8]| {
+            let end = field.iter().position(|b| *b == 0).unwrap_or(field.len());
+            String::from_utf8(field[..end].to_vec()).unwrap()
+        };
+        assert_eq!(tar.len() % BLOCK, 0);
+        assert!(tar[tar.len() - 2 * BLOCK..].iter().all(|b| *b == 0));
+
+        let mut found = vec![];
+        let mut at = 0;
+        while at < tar.len() - 2 * BLOCK {
+            let header = &tar[at..at + BLOCK];
+            assert_eq!(&header[257..265], b"ustar\x0000");
+            let mut blank = header.to_vec();
+            blank[148..156].fill(b' ');
+            let sum = blank.iter().map(|b| *b as usize).sum::<usize>();
+            assert_eq!(octal(&header[148..156]), sum, "checksum at {}", at);
+
+            let (prefix, name) = (text(&header[345..500]), text(&header[..100]));
+            let path = match prefix.is_empty() {
+                true => name,
+                false => format!("{}/{}", prefix, name),
+            };
+            let size = octal(&header[124..136]);
+            found.push((path, header[156], octal(&header[100..108]) as u32, size));
+            at += BLOCK + size.div_ceil(BLOCK) * BLOCK;
+        }
+        found
+    }
+
+    #[test]
+    fn write_tar_writes_ustar_headers() {
+        temp_repo("write-tar");
+        let long = "d".repeat(90);
+        let file = blob("hello");
+        let deep = tree(&[(Mode::ExeBlob, &"f".repeat(40), &file)]);
+        let top = tree(&[
+            (Mode::FileBlob, "a", &file),
+            (Mode::SubDir, &long, &deep),
+            (Mode::SymLink, "link", &blob("a")),
+        ]);
+
+        let mut tar = vec![];
+        write_tar(&top, "p/", 1112911993, &mut tar).unwrap();
+        let deep_path = format!("p/{}/{}", long, "f".repeat(40));
+        assert!(deep_path.len() > 100);
+        assert_eq!(
+            entries(&tar),
+            [
+                ("p/".to_string(), b'5', 0o755, 0),
+                ("p/a".to_string(), b'0', 0o644, 5),
+                (format!("p/{}/", long), b'5', 0o755, 0),
+                (deep_path, b'0', 0o755, 5),
+                ("p/link".to_string(), b'2', 0o777, 0),
+            ]
+        );
+        // The long path is split at a `/` between the prefix and name fields
+        let header = &tar[3 * BLOCK + BLOCK..];
+        assert!(header[345..].starts_with(format!("p/{}\0", long).as_bytes()));
+        assert!(header.starts_with(format!("{}\0", "f".repeat(40)).as_bytes()));
+        assert!(write_tar(&top, "p", 0, &mut vec![]).is_err());
+    }
+}
@@ -0,0 +1,126 @@
+//! A naive line blame, following first parents back from a commit and diffing the file
+//! between each commit and its parent.
+use anyhow::Context;
+
+use crate::{rev, store::read_blob, walk::CommitWalk};
+
+/// The commit that last changed each line of `path` as of `commit`, as `(sha, line)`.
+///
+/// Lines are attributed to the first commit walking back whose parent doesn't have them,
+/// lines still there at the root commit belong to it.
+pub fn blame(commit: &str, path: &str) -> anyhow::Result<Vec<(String, String)>> {
+    let lines = file_lines(commit, path)
+        .with_context(|| format!("no such path '{}' in {}", path, commit))?;
+    let mut owners = vec![String::new(); lines.len()];
+    // Every line not yet attributed and where it is in the file at `sha`
+    let mut tracked = (0..lines.len())
+        .map(|line| (line, line))
+        .collect::<Vec<_>>();
+    let mut current = lines.clone();
+
+    for walked in CommitWalk::new(commit).first_parent(true) {
+        let walked = walked?;
+        if tracked.is_empty() {
+            break;
+        }
+        let parent_lines = walked
+            .parents
+            .first()
+            .and_then(|parent| file_lines(parent, path).ok())
+            .unwrap_or_default();
+        let matches = common_lines(&parent_lines, &current);
+
+        let mut still = vec![];
+        for (line, at) in tracked {
+            match matches[at] {
+                Some(parent_at) => still.push((line, parent_at)),
+                None => owners[line] = walked.sha.clone(),
+            }
+        }
+        tracked = still;
+        current = parent_lines;
+    }
+    Ok(owners.into_iter().zip(lines).collect())
+}
+
+/// The lines of the file at `path` in `commit`.
+fn file_lines(commit: &str, path: &str) -> anyhow::Result<Vec<String>> {
+    let tree = rev::peel(commit, Some("tree"))?;
+    let content = read_blob(&rev::lookup_path(&tree, path)?)
+        .with_context(|| format!("'{}' is not a file", path))?;
+    Ok(String::from_utf8_lossy(&content)
+        .lines()
+        .map(str::to_string)
+        .collect())
+}
+
+/// For each line of `new` the index of the same line in `old` if it is part of their longest
+/// common subsequence, the lines it has no match for are the ones `new` added.
+fn common_lines(old: &[String], new: &[String]) -> Vec<Option<usize>> {
+    // lcs[i][j] is the LCS length of old[i..] and new[j..]
+    let mut lcs = vec![vec![0u32; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lcs[i][j] = if old[i] == new[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let mut matches = vec![None; new.len()];
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() && j < new.len() {
+        if old[i] == new[j] {
+            matches[j] = Some(i);
+            i += 1;
+            j += 1;
+        } else if lcs[i + 1][j] >= lcs[i][j + 1] {
+            i += 1;
+        } else {
+            j += 1;
+        }
+    }
+    matches
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        testutil::{blob, commit, temp_repo, tree},
+        tree::Mode,
+    };
+
+    #[test]
+    fn blame_follows_first_parents() {
+        temp_repo("blame");
+        let file = |content: &str| tree(&[(Mode::FileBlob, "f", &blob(content))]);
+        let first = commit(&file("a\nb\n"), &[], "first");
+        let second = commit(&file("a\nB\nc\n"), &[&first], "second");
+        // The merged side branch adds `side`, blame only walks the first parent so the
+        // merge is where it shows up
+        let side = commit(&file("a\nb\nside\n"), &[&first], "side");
+        let merge = commit(&file("a\nB\nc\nside\n"), &[&second, &side], "merge");
+
+        let owners = blame(&merge, "f").unwrap();
+        let expected = [
+            (&first, "a"),
+            (&second, "B"),
+            (&second, "c"),
+            (&merge, "side"),
+        ]
+        .map(|(sha, line)| (sha.clone(), line.to_string()));
+        assert_eq!(owners, expected);
+        assert!(blame(&merge, "missing").is_err());
+    }
+
+    #[test]
+    fn common_lines_matches_the_longest_common_subsequence() {
+        let lines = |text: &str| text.split(' ').map(str::to_string).collect::<Vec<_>>();
+        let matches = common_lines(&lines("a b c d"), &lines("a x c d e"));
+        assert_eq!(matches, [Some(0), None, Some(2), Some(3), None]);
+        assert_eq!(common_lines(&[], &lines("a")), [None]);
+    }
+}
@@ -0,0 +1,54 @@
+use std::{
+    env,
+    io::{self, IsTerminal},
+};
+
+use clap::ValueEnum;
+
+use crate::tree::Mode;
+
+const RESET: &str = "\x1b[0m";
+const BOLD_BLUE: &str = "\x1b[1;34m";
+const GREEN: &str = "\x1b[32m";
+const CYAN: &str = "\x1b[36m";
+const MAGENTA: &str = "\x1b[35m";
+
+/// When to colorize output, mirrors git's `--color[=<when>]`.
+#[derive(Clone, Copy, Debug, Default, ValueEnum)]
+pub enum ColorWhen {
+    /// Only when stdout is a terminal and `NO_COLOR` is not set.
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorWhen {
+    /// Resolve the choice against the environment, `--color=always` wins over `NO_COLOR`.
+    pub fn enabled(self) -> bool {
+        match self {
+            ColorWhen::Always => true,
+            ColorWhen::Never => false,
+            ColorWhen::Auto => {
+                env::var_os("NO_COLOR").is_none_or(|v| v.is_empty()) && io::stdout().is_terminal()
+            }
+        }
+    }
+}
+
+/// Wrap `s` in the escape codes for an entry of the given `mode`.
+///
+/// Regular files are left alone so a colored listing only highlights the unusual entries.
+pub fn paint(mode: Mode, s: &str, enabled: bool) -> String {
+    let color = match mode {
+        Mode::SubDir => BOLD_BLUE,
+        Mode::ExeBlob => GREEN,
+        Mode::SymLink => CYAN,
+        Mode::SubMod => MAGENTA,
+        Mode::FileBlob => return s.to_string(),
+    };
+    if !enabled {
+        return s.to_string();
+    }
+    format!("{}{}{}", color, s, RESET)
+}
@@ -0,0 +1,131 @@
+//! Reading `.idiot/config` and `.gitattributes`, only as much of git's formats as the
+//! commands need.
+use std::{fs, io::Write, path::Path};
+
+use anyhow::Context;
+
+use crate::{repo, tree::glob_match};
+
+/// The settings in `.idiot/config`, as `section[.subsection].key` names.
+#[derive(Debug, Default)]
+pub struct Config {
+    entries: Vec<(String, String)>,
+}
+
+impl Config {
+    /// Read `.idiot/config`, a missing file is an empty config.
+    pub fn read() -> Self {
+        Self::read_file(&repo::dir().join("config"))
+    }
+
+    /// Read another file in the same format, like `.gitmodules`.
+    pub fn read_file(path: &Path) -> Self {
+        let Ok(content) = fs::read_to_string(path) else {
+            return Self::default();
+        };
+        let mut entries = vec![];
+        let mut section = String::new();
+        for line in content.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+                continue;
+            }
+            if let Some(header) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+                // Sections and keys are case insensitive, subsections are not
+                section = match header.split_once(' ') {
+                    Some((name, sub)) => {
+                        format!("{}.{}", name.to_lowercase(), sub.trim().trim_matches('"'))
+                    }
+                    None => header.to_lowercase(),
+                };
+                continue;
+            }
+            let (key, value) = line.split_once('=').unwrap_or((line, "true"));
+            entries.push((
+                format!("{}.{}", section, key.trim().to_lowercase()),
+                value.trim().trim_matches('"').to_string(),
+            ));
+        }
+        Self { entries }
+    }
+
+    /// The value of `name` (`diff.hex.textconv`), the last one wins when it is set twice.
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.entries
+            .iter()
+            .rev()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// The subsections of `section` (the `origin` of `[remote "origin"]`) in the order
+    /// they first appear.
+    pub fn subsections(&self, section: &str) -> Vec<&str> {
+        let mut found = vec![];
+        for (key, _) in &self.entries {
+            let sub = key
+                .strip_prefix(section)
+                .and_then(|rest| rest.strip_prefix('.'))
+                .and_then(|rest| rest.rsplit_once('.'))
+                .map(|(sub, _)| sub);
+            if let Some(sub) = sub.filter(|sub| !found.contains(sub)) {
+                found.push(sub);
+            }
+        }
+        found
+    }
+
+    /// Add a `[<section> "<sub>"]` section with `entries` to the end of `.idiot/config`.
+    pub fn append_section(
+        section: &str,
+        sub: &str,
+        entries: &[(&str, &str)],
+    ) -> anyhow::Result<()> {
+        let path = repo::dir().join("config");
+        let content = fs::read_to_string(&path).unwrap_or_default();
+        let mut text = String::new();
+        if !content.is_empty() && !content.ends_with('\n') {
+            text.push('\n');
+        }
+        text.push_str(&format!("[{} \"{}\"]\n", section, sub));
+        for (key, value) in entries {
+            text.push_str(&format!("\t{} = {}\n", key, value));
+        }
+        fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .and_then(|mut file| file.write_all(text.as_bytes()))
+            .context("writing the config")
+    }
+}
+
+/// The value of the attribute `attr` for `path` from `.gitattributes` at the top of the
+/// work tree, `None` when it isn't set to a value (unset, `-attr` and bare `attr` included).
+pub fn attribute(path: &str, attr: &str) -> Option<String> {
+    let content = fs::read_to_string(repo::work_tree().ok()?.join(".gitattributes")).ok()?;
+    let name = path.rsplit('/').next().unwrap_or(path);
+    let mut found = None;
+    for line in content.lines() {
+        let mut words = line.split_whitespace();
+        let Some(pattern) = words.next().filter(|p| !p.starts_with('#')) else {
+            continue;
+        };
+        // Like `.gitignore` a pattern without a `/` matches the file name at any depth
+        let matched = match pattern.trim_start_matches('/') {
+            p if pattern.contains('/') => glob_match(p.as_bytes(), path.as_bytes()),
+            p => glob_match(p.as_bytes(), name.as_bytes()),
+        };
+        if !matched {
+            continue;
+        }
+        for word in words {
+            match word.split_once('=') {
+                Some((key, value)) if key == attr => found = Some(value.to_string()),
+                None if word.trim_start_matches(['-', '!']) == attr => found = None,
+                _ => {}
+            }
+        }
+    }
+    found
+}
@@ -0,0 +1,413 @@
+//! Fetching objects and refs from another repository, one on the local filesystem or one
+//! served over git's "dumb" HTTP protocol, the repository's files served as they are, see
+//! `gitprotocol-http(5)`.
+use std::{
+    collections::HashSet,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use reqwest::{blocking::Client, StatusCode};
+use sha1::{Digest, Sha1};
+
+use crate::{
+    decomp_obj, log, pack,
+    progress::Progress,
+    store::{read_object, read_object_in, write_object},
+    tree::{GitObject, Mode, ObjType},
+    IDIOT,
+};
+
+/// A repository on the local filesystem, read directly.
+pub struct Local {
+    /// The top of the repository, where a clone records it came from.
+    path: PathBuf,
+    /// Its `.idiot`, or `path` itself for a bare repository.
+    dir: PathBuf,
+    /// Whether its packs have been unpacked into the object store yet.
+    unpacked: bool,
+}
+
+impl Local {
+    /// The repository at `path`, with a `.idiot` or bare.
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let path = fs::canonicalize(path)
+            .with_context(|| format!("repository '{}' does not exist", path.display()))?;
+        let dir = if path.join(IDIOT).is_dir() {
+            path.join(IDIOT)
+        } else if path.join("HEAD").is_file() && path.join("objects").is_dir() {
+            path.clone()
+        } else {
+            anyhow::bail!("'{}' is not a repository", path.display())
+        };
+        Ok(Self {
+            path,
+            dir,
+            unpacked: false,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The repository's refs as `(name, sha)` sorted by name, loose ones overriding those
+    /// in `packed-refs`, and `HEAD` first when it points at a commit. Symbolic refs other
+    /// than `HEAD` are left out.
+    pub fn refs(&self) -> anyhow::Result<Vec<(String, String)>> {
+        let mut refs = vec![];
+        self.loose_refs(&self.dir.join("refs"), "refs", &mut refs)?;
+        let packed = fs::read_to_string(self.dir.join("packed-refs")).unwrap_or_default();
+        for line in packed.lines() {
+            if line.starts_with('#') || line.starts_with('^') || line.is_empty() {
+                continue;
+            }
+            let (sha, name) = line
+                .split_once(' ')
+                .with_context(|| format!("bad packed-refs line '{}'", line))?;
+            if !refs.iter().any(|(loose, _)| loose == name) {
+                refs.push((name.to_string(), sha.to_string()));
+            }
+        }
+        refs.sort();
+
+        let head = match self.head_branch()? {
+            Some(branch) => refs
+                .iter()
+                .find(|(name, _)| *name == branch)
+                .map(|r| r.1.clone()),
+            None => Some(
+                fs::read_to_string(self.dir.join("HEAD"))?
+                    .trim()
+                    .to_string(),
+            ),
+        };
+        if let Some(head) = head {
+            refs.insert(0, ("HEAD".to_string(), head));
+        }
+        Ok(refs)
+    }
+
+    /// The full name of the branch `HEAD` is on, `None` when it is detached.
+    pub fn head_branch(&self) -> anyhow::Result<Option<String>> {
+        let head = fs::read_to_string(self.dir.join("HEAD")).context("reading HEAD")?;
+        Ok(head.trim().strip_prefix("ref: ").map(str::to_string))
+    }
+
+    /// The object `sha`, read loose or, the first time one isn't loose, from the packs in
+    /// `objects/pack`, every object of which is stored as well.
+    pub fn object(&mut self, sha: &str, store: bool) -> anyhow::Result<Vec<u8>> {
+        let objects = self.dir.join("objects");
+        if let Ok(obj) = read_object_in(&objects, sha) {
+            return Ok(obj);
+        }
+        if !self.unpacked {
+            self.unpacked = true;
+            let mut packs = fs::read_dir(objects.join("pack"))
+                .map(|entries| {
+                    entries
+                        .filter_map(|e| Some(e.ok()?.path()))
+                        .filter(|p| p.extension().is_some_and(|ext| ext == "pack"))
+                        .collect::<Vec<_>>()
+                })
+                .unwrap_or_default();
+            packs.sort();
+            for pack in packs {
+                log::info!("reading pack {}", pack.display());
+                let bytes = fs::read(&pack)
+                    .with_context(|| format!("reading pack '{}'", pack.display()))?;
+                unpack(&bytes, store)?;
+            }
+            if let Ok(obj) = read_object(sha) {
+                return Ok(obj);
+            }
+        }
+        anyhow::bail!("object {} is missing from {}", sha, self.path.display())
+    }
+
+    /// Push the loose refs in `dir` (named `prefix`) and the directories below it.
+    fn loose_refs(
+        &self,
+        dir: &Path,
+        prefix: &str,
+        out: &mut Vec<(String, String)>,
+    ) -> anyhow::Result<()> {
+        let Ok(entries) = fs::read_dir(dir) else {
+            return Ok(());
+        };
+        for entry in entries {
+            let entry = entry?;
+            let name = format!("{}/{}", prefix, entry.file_name().to_string_lossy());
+            if entry.file_type()?.is_dir() {
+                self.loose_refs(&entry.path(), &name, out)?;
+            } else {
+                let content = fs::read_to_string(entry.path())?;
+                if !content.starts_with("ref: ") && !name.ends_with(".lock") {
+                    out.push((name, content.trim().to_string()));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A repository served over dumb HTTP, `url` is where its `info/refs` lives under.
+pub struct DumbHttp {
+    client: Client,
+    url: String,
+    /// Packs already downloaded and unpacked, by file name.
+    packs: HashSet<String>,
+}
+
+impl DumbHttp {
+    pub fn new(url: &str) -> Self {
+        Self {
+            client: Client::new(),
+            url: url.trim_end_matches('/').to_string(),
+            packs: HashSet::new(),
+        }
+    }
+
+    /// The remote's refs as `(name, sha)` from `info/refs`, which `git update-server-info`
+    /// keeps up to date, leaving out the `^{}` lines of peeled tags.
+    pub fn refs(&self) -> anyhow::Result<Vec<(String, String)>> {
+        let bytes = self.get("info/refs")?.with_context(|| {
+            format!(
+                "{} has no info/refs, does the server run 'git update-server-info'?",
+                self.url
+            )
+        })?;
+        let mut refs = vec![];
+        for line in String::from_utf8_lossy(&bytes).lines() {
+            let (sha, name) = line
+                .split_once('\t')
+                .with_context(|| format!("bad info/refs line '{}'", line))?;
+            if !name.ends_with("^{}") {
+                refs.push((name.to_string(), sha.to_string()));
+            }
+        }
+        Ok(refs)
+    }
+
+    /// The object `sha`, downloaded loose or, when the remote only has it packed, from the
+    /// packs it lists in `objects/info/packs`, every object of which is stored as well.
+    pub fn object(&mut self, sha: &str, store: bool) -> anyhow::Result<Vec<u8>> {
+        // The SHA can come from the server's `info/refs` or objects, it goes in a URL
+        anyhow::ensure!(
+            sha.len() == 40 && sha.bytes().all(|ch| ch.is_ascii_hexdigit()),
+            "not a valid object name '{}'",
+            sha
+        );
+        if let Some(bytes) = self.get(&format!("objects/{}/{}", &sha[..2], &sha[2..]))? {
+            return decomp_obj(&bytes).with_context(|| format!("decompressing object {}", sha));
+        }
+        let list = self.get("objects/info/packs")?.unwrap_or_default();
+        for line in String::from_utf8_lossy(&list).lines() {
+            let Some(name) = line.strip_prefix("P ").map(str::trim) else {
+                continue;
+            };
+            if !self.packs.insert(name.to_string()) {
+                continue;
+            }
+            log::info!("downloading pack {}", name);
+            let bytes = self
+                .get(&format!("objects/pack/{}", name))?
+                .with_context(|| format!("pack {} is listed but missing", name))?;
+            unpack(&bytes, store)?;
+            if let Ok(obj) = read_object(sha) {
+                return Ok(obj);
+            }
+        }
+        anyhow::bail!("object {} is missing from {}", sha, self.url)
+    }
+
+    /// The file at `path` under the repository, `None` when the server doesn't have it.
+    fn get(&self, path: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        let url = format!("{}/{}", self.url, path);
+        log::debug!("GET {}", url);
+        let response = self
+            .client
+            .get(&url)
+            .send()
+            .with_context(|| format!("fetching {}", url))?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        let response = response
+            .error_for_status()
+            .with_context(|| format!("fetching {}", url))?;
+        Ok(Some(response.bytes()?.to_vec()))
+    }
+}
+
+/// Store every object of the pack `bytes` as a loose object.
+fn unpack(bytes: &[u8], store: bool) -> anyhow::Result<()> {
+    for (kind, content) in pack::read_pack(bytes)? {
+        let mut obj = format!("{} {}\0", kind, content.len()).into_bytes();
+        obj.extend(content);
+        write_object(&obj, store)?;
+    }
+    Ok(())
+}
+
+/// The objects `obj` (with its header) points at, a commit's tree and parents, a tree's
+/// entries and a tag's object. Gitlinks are left out, they live in another repository.
+pub fn links(obj: &[u8]) -> anyhow::Result<Vec<String>> {
+    if obj.starts_with(b"tag ") {
+        let body = obj.splitn(2, |ch| ch == &b'\0').nth(1).unwrap_or_default();
+        let object = String::from_utf8_lossy(body)
+            .lines()
+            .next()
+            .and_then(|line| line.strip_prefix("object "))
+            .map(str::to_string)
+            .context("tag has no object")?;
+        return Ok(vec![object]);
+    }
+    if !obj.starts_with(b"tree ") && !obj.starts_with(b"commit ") {
+        return Ok(vec![]);
+    }
+    Ok(match GitObject::try_from_bytes(obj)?.obj_type {
+        ObjType::Commit { tree, parents, .. } => [vec![tree], parents].concat(),
+        ObjType::Tree { objs, .. } => objs
+            .iter()
+            .filter(|o| !matches!(o.mode, Mode::SubMod))
+            .filter_map(|o| o.sha.as_ref().map(hex::encode))
+            .collect(),
+        ObjType::Blob { .. } => vec![],
+    })
+}
+
+/// Make sure every object reachable from `tips` is in the object store, getting the ones
+/// that aren't from `source`.
+///
+/// Objects already here are still walked through, a fetch that was cut short can have left
+/// a commit without its history.
+pub fn copy_reachable(
+    tips: &[String],
+    mut source: impl FnMut(&str) -> anyhow::Result<Vec<u8>>,
+    store: bool,
+    quiet: bool,
+) -> anyhow::Result<()> {
+    let mut progress = Progress::new("Receiving objects", false, quiet);
+    let mut wanted = tips.to_vec();
+    let mut seen = HashSet::new();
+    while let Some(sha) = wanted.pop() {
+        if !seen.insert(sha.clone()) {
+            continue;
+        }
+        let obj = match read_object(&sha) {
+            Ok(obj) => obj,
+            Err(_) => {
+                let obj = source(&sha)?;
+                let actual = hex::encode(Sha1::digest(&obj));
+                anyhow::ensure!(actual == sha, "object {} hashes to {}", sha, actual);
+                write_object(&obj, store)?;
+                progress.tick();
+                obj
+            }
+        };
+        wanted.extend(links(&obj).with_context(|| format!("reading object {}", sha))?);
+    }
+    progress.finish();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader, Write},
+        net::TcpListener,
+        thread,
+    };
+
+    use super::*;
+    use crate::{
+        repo,
+        testutil::{blob, commit, temp_repo, tree},
+    };
+
+    /// Serve the files under `root` over HTTP on a free local port like a dumb server
+    /// would, returns the URL they are under.
+    fn serve(root: PathBuf) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut lines = BufReader::new(&stream).lines();
+                let request = lines.next().unwrap().unwrap();
+                // The headers, up to the blank line before the (empty) body
+                for line in lines.by_ref() {
+                    if line.unwrap().is_empty() {
+                        break;
+                    }
+                }
+                let path = request.split(' ').nth(1).unwrap().trim_start_matches('/');
+                let (status, body) = match fs::read(root.join(path)) {
+                    Ok(body) => ("200 OK", body),
+                    Err(_) => ("404 Not Found", vec![]),
+                };
+                let head = format!(
+                    "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    status,
+                    body.len()
+                );
+                stream.write_all(head.as_bytes()).unwrap();
+                stream.write_all(&body).unwrap();
+            }
+        });
+        url
+    }
+
+    #[test]
+    fn dumb_http_copies_loose_and_packed_objects() {
+        temp_repo("dumb-http-remote");
+        let old = blob("old");
+        let first_tree = tree(&[(Mode::FileBlob, "a", &old)]);
+        let first = commit(&first_tree, &[], "first");
+        let new = blob("new");
+        let second = commit(&tree(&[(Mode::FileBlob, "a", &new)]), &[&first], "second");
+        // The first commit and everything it needs only in a pack
+        let (pack, _) =
+            pack::write_pack(&[first.clone(), first_tree.clone(), old.clone()]).unwrap();
+        for sha in [&first, &first_tree, &old] {
+            fs::remove_file(crate::store::object_path(sha).unwrap()).unwrap();
+        }
+        let objects = repo::objects();
+        fs::create_dir_all(objects.join("pack")).unwrap();
+        fs::write(objects.join("pack/pack-test.pack"), pack).unwrap();
+        fs::create_dir_all(objects.join("info")).unwrap();
+        fs::write(objects.join("info/packs"), "P pack-test.pack\n\n").unwrap();
+        fs::create_dir_all(repo::dir().join("info")).unwrap();
+        fs::write(
+            repo::dir().join("info/refs"),
+            format!(
+                "{second}\trefs/heads/master\n{first}\trefs/tags/v1\n{first}\trefs/tags/v1^{{}}\n"
+            ),
+        )
+        .unwrap();
+        let mut remote = DumbHttp::new(&serve(repo::dir()));
+
+        temp_repo("dumb-http-local");
+        let refs = remote.refs().unwrap();
+        assert_eq!(
+            refs,
+            [
+                ("refs/heads/master".to_string(), second.clone()),
+                ("refs/tags/v1".to_string(), first.clone())
+            ]
+        );
+        copy_reachable(
+            std::slice::from_ref(&second),
+            |sha| remote.object(sha, false),
+            false,
+            true,
+        )
+        .unwrap();
+        for sha in [&second, &first, &first_tree, &old, &new] {
+            assert!(read_object(sha).is_ok(), "{} was not copied", sha);
+        }
+        assert!(remote.object("../../etc/passwd", false).is_err());
+    }
+}
@@ -0,0 +1,216 @@
+//! Reading and writing git's binary index (`.git/index`, "DIRC") so a working directory
+//! can be shared with real git, see `gitformat-index(5)`.
+use std::fs;
+
+use anyhow::Context;
+use sha1::{Digest, Sha1};
+
+use crate::{repo, tree::Mode};
+
+/// `ctime` through `size`, ten 32-bit fields.
+const STAT_SIZE: usize = 40;
+/// The stat fields, the SHA and the flags, everything before the name.
+const ENTRY_HEADER: usize = STAT_SIZE + 20 + 2;
+
+/// One staged file.
+#[derive(Debug)]
+pub struct IndexEntry {
+    pub mode: Mode,
+    pub sha: [u8; 20],
+    /// The merge stage, `0` unless the path is conflicted.
+    pub stage: u8,
+    /// The file's modification time (seconds, nanoseconds) and size when it was staged,
+    /// all zero for entries that never matched a file, which makes git re-check them.
+    pub mtime: (u32, u32),
+    pub size: u32,
+    /// The path relative to the work tree, always with `/` separators.
+    pub path: String,
+}
+
+/// Read `.idiot/index`, a repo that has never staged anything has an empty index.
+pub fn read_index() -> anyhow::Result<Vec<IndexEntry>> {
+    let path = repo::dir().join("index");
+    if !path.exists() {
+        return Ok(vec![]);
+    }
+    let bytes = fs::read(&path).context("reading the index")?;
+    parse_index(&bytes)
+}
+
+/// Parse an index in version 2 or 3 after checking its trailing checksum.
+pub fn parse_index(bytes: &[u8]) -> anyhow::Result<Vec<IndexEntry>> {
+    anyhow::ensure!(bytes.len() >= 12 + 20, "index file is too short");
+    let (content, checksum) = bytes.split_at(bytes.len() - 20);
+    anyhow::ensure!(
+        Sha1::digest(content).as_slice() == checksum,
+        "index checksum mismatch"
+    );
+    anyhow::ensure!(&content[..4] == b"DIRC", "index has a bad signature");
+    let version = be_u32(&content[4..]);
+    anyhow::ensure!(
+        (2..=3).contains(&version),
+        "index version {} is not supported",
+        version
+    );
+    let count = be_u32(&content[8..]) as usize;
+
+    let mut entries = Vec::with_capacity(count);
+    let mut at = 12;
+    for _ in 0..count {
+        let entry = content
+            .get(at..)
+            .filter(|rest| rest.len() >= ENTRY_HEADER)
+            .context("index entry is cut short")?;
+        let flags = u16::from_be_bytes([entry[STAT_SIZE + 20], entry[STAT_SIZE + 21]]);
+        // Version 3 entries with the extended flag carry two more flag bytes
+        let name_at = ENTRY_HEADER + if flags & 0x4000 != 0 { 2 } else { 0 };
+        let name_len = entry
+            .get(name_at..)
+            .and_then(|rest| rest.iter().position(|b| *b == b'\0'))
+            .context("index entry name is not terminated")?;
+
+        let raw_mode = be_u32(&entry[24..]);
+        let mode = format!("{:o}", raw_mode)
+            .parse()
+            .ok()
+            .and_then(Mode::try_new)
+            .with_context(|| format!("index entry has a bad mode {:o}", raw_mode))?;
+        entries.push(IndexEntry {
+            mode,
+            sha: entry[STAT_SIZE..STAT_SIZE + 20].try_into().unwrap(),
+            stage: ((flags >> 12) & 0b11) as u8,
+            mtime: (be_u32(&entry[8..]), be_u32(&entry[12..])),
+            size: be_u32(&entry[36..]),
+            path: String::from_utf8_lossy(&entry[name_at..name_at + name_len]).to_string(),
+        });
+
+        // Entries are NUL padded (1 to 8 bytes) to a multiple of eight
+        at += (name_at + name_len + 8) & !7;
+    }
+    Ok(entries)
+}
+
+/// Write `entries` (sorted by path) as a version 2 `.idiot/index`, replacing it whole.
+///
+/// Only the modification time and size are kept of the stat data, git re-checks the
+/// content of entries whose other fields don't match the file.
+pub fn write_index(entries: &[IndexEntry]) -> anyhow::Result<()> {
+    let mut bytes = b"DIRC".to_vec();
+    bytes.extend(2u32.to_be_bytes());
+    bytes.extend(u32::try_from(entries.len())?.to_be_bytes());
+    for e in entries {
+        let start = bytes.len();
+        // The mode is stored as the number its octal digits spell
+        let mode = u32::from_str_radix(&(e.mode as usize).to_string(), 8)?;
+        let (secs, nanos) = e.mtime;
+        // ctime, mtime, dev, ino, mode, uid, gid, size
+        for field in [secs, nanos, secs, nanos, 0, 0, mode, 0, 0, e.size] {
+            bytes.extend(field.to_be_bytes());
+        }
+        bytes.extend(e.sha);
+        let flags = (u16::from(e.stage & 0b11) << 12) | e.path.len().min(0xfff) as u16;
+        bytes.extend(flags.to_be_bytes());
+        bytes.extend(e.path.as_bytes());
+        let len = bytes.len() - start;
+        bytes.resize(start + ((len + 8) & !7), 0);
+    }
+    let checksum = Sha1::digest(&bytes);
+    bytes.extend(checksum);
+
+    // Written aside and renamed so nothing ever sees half an index
+    let lock = repo::dir().join("index.lock");
+    fs::write(&lock, bytes).context("writing the index")?;
+    fs::rename(&lock, repo::dir().join("index")).context("writing the index")?;
+    Ok(())
+}
+
+fn be_u32(bytes: &[u8]) -> u32 {
+    u32::from_be_bytes(bytes[..4].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::temp_repo;
+
+    /// `a` and the executable `dir/b` staged by `git add`.
+    const GIT_V2: &str = "4449524300000002000000026ad056c004c6bdeb6ad056c004c6bdeb0000fe0000f8a238\
+        000081a40000000000000000000000045626abf0f72e58d7a153368ba57db4c673c0e171000161006ad056c0\
+        04db3a286ad056c004c6bdeb0000fe0000f8a23a000081ed000000000000000000000004f719efd430d52bcf\
+        c8566a43b2eb655688d3887100056469722f620000000000fd22d7fae32f4f3ec430e3ca564b90c85859560f";
+    /// The same with `c` added by `git add -N`, which needs version 3's extended flags.
+    const GIT_V3: &str = "4449524300000003000000036ad056c004c6bdeb6ad056c004c6bdeb0000fe0000f8a238\
+        000081a40000000000000000000000045626abf0f72e58d7a153368ba57db4c673c0e1710001610000000000\
+        0000000000000000000000000000000000000000000081a4000000000000000000000000e69de29bb2d1d643\
+        4b8b29ae775ad8c2e48c53914001200063000000000000006ad056c004db3a286ad056c004c6bdeb0000fe00\
+        00f8a23a000081ed000000000000000000000004f719efd430d52bcfc8566a43b2eb655688d3887100056469\
+        722f62000000000070db7c71a386246868f3191646418bb7b6af0eb3";
+
+    /// What is kept of each entry, as `(mode, sha, path)`.
+    fn summary(entries: &[IndexEntry]) -> Vec<(usize, String, &str)> {
+        entries
+            .iter()
+            .map(|e| (e.mode as usize, hex::encode(e.sha), e.path.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn parse_index_reads_what_git_wrote() {
+        let entries = parse_index(&hex::decode(GIT_V2).unwrap()).unwrap();
+        assert_eq!(
+            summary(&entries),
+            [
+                (
+                    100644,
+                    "5626abf0f72e58d7a153368ba57db4c673c0e171".to_string(),
+                    "a"
+                ),
+                (
+                    100755,
+                    "f719efd430d52bcfc8566a43b2eb655688d38871".to_string(),
+                    "dir/b"
+                ),
+            ]
+        );
+        assert_eq!(entries[0].mtime, (0x6ad056c0, 0x04c6bdeb));
+        assert_eq!(entries[0].size, 4);
+
+        let v3 = parse_index(&hex::decode(GIT_V3).unwrap()).unwrap();
+        assert_eq!(
+            v3.iter().map(|e| e.path.as_str()).collect::<Vec<_>>(),
+            ["a", "c", "dir/b"]
+        );
+        assert_eq!(
+            hex::encode(v3[1].sha),
+            "e69de29bb2d1d6434b8b29ae775ad8c2e48c5391"
+        );
+    }
+
+    #[test]
+    fn parse_index_checks_the_checksum() {
+        let mut bytes = hex::decode(GIT_V2).unwrap();
+        bytes[12] ^= 1;
+        let err = parse_index(&bytes).unwrap_err();
+        assert!(
+            format!("{:#}", err).contains("checksum mismatch"),
+            "{:#}",
+            err
+        );
+    }
+
+    #[test]
+    fn write_index_round_trips() {
+        temp_repo("index-round-trip");
+        let entries = parse_index(&hex::decode(GIT_V3).unwrap()).unwrap();
+        write_index(&entries).unwrap();
+        let read = read_index().unwrap();
+        assert_eq!(summary(&read), summary(&entries));
+        assert_eq!(
+            read.iter().map(|e| (e.mtime, e.size)).collect::<Vec<_>>(),
+            entries
+                .iter()
+                .map(|e| (e.mtime, e.size))
+                .collect::<Vec<_>>()
+        );
+    }
+}
@@ -0,0 +1,71 @@
+//! Leveled diagnostics on stderr, turned on with `--log-level`.
+//!
+//! Nothing is printed by default, stdout only ever carries the command's real output.
+use std::sync::atomic::{AtomicU8, Ordering};
+
+use clap::ValueEnum;
+
+static LEVEL: AtomicU8 = AtomicU8::new(0);
+
+/// What a command does and how long it takes, from `--log-level=info`.
+pub const INFO: u8 = 1;
+/// Every file and object a command looks at, from `--log-level=debug`.
+pub const DEBUG: u8 = 2;
+
+/// How much `--log-level` prints, each level includes the ones before it.
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum Level {
+    /// What the command does and how long it takes.
+    Info,
+    /// Also every file and object it looks at.
+    Debug,
+}
+
+/// Print the diagnostics of `level` from here on, none for `None`.
+pub fn set_level(level: Option<Level>) {
+    let level = match level {
+        None => 0,
+        Some(Level::Info) => INFO,
+        Some(Level::Debug) => DEBUG,
+    };
+    LEVEL.store(level, Ordering::Relaxed);
+}
+
+pub fn enabled(level: u8) -> bool {
+    LEVEL.load(Ordering::Relaxed) >= level
+}
+
+/// Print an `info: ` line to stderr at `--log-level=info` and above, formatted like `eprintln!`.
+macro_rules! info {
+    ($($arg:tt)*) => {
+        if $crate::log::enabled($crate::log::INFO) {
+            eprintln!("info: {}", format_args!($($arg)*));
+        }
+    };
+}
+
+/// Print a `debug: ` line to stderr at `--log-level=debug`, formatted like `eprintln!`.
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        if $crate::log::enabled($crate::log::DEBUG) {
+            eprintln!("debug: {}", format_args!($($arg)*));
+        }
+    };
+}
+
+pub(crate) use {debug, info};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn debug_level_turns_on_debug_and_info_lines() {
+        set_level(Some(Level::Debug));
+        assert!(enabled(DEBUG) && enabled(INFO));
+        set_level(Some(Level::Info));
+        assert!(enabled(INFO) && !enabled(DEBUG));
+        set_level(None);
+        assert!(!enabled(INFO));
+    }
+}
@@ -1,168 +1,2675 @@
-#![feature(lazy_cell)]
-
 use std::{
+    collections::{HashMap, HashSet},
+    env,
+    fmt::Write as _,
     fs,
-    io::{self, Read},
+    io::{self, BufRead, BufReader, BufWriter, Read, Write},
+    os::unix::fs::PermissionsExt,
+    path::Path,
+    process::{self, ExitCode},
+    time::{Instant, SystemTime, UNIX_EPOCH},
 };
 
 use anyhow::Context;
-use clap::{Parser, Subcommand};
+use clap::{ArgGroup, Parser, Subcommand};
+use color::ColorWhen;
 use flate2::{
     bufread::{ZlibDecoder, ZlibEncoder},
     Compression,
 };
 use sha1::{Digest, Sha1};
 
+mod archive;
+mod blame;
+mod color;
+mod config;
+mod fetch;
+mod index;
+mod log;
+mod pack;
+mod progress;
+mod repo;
+mod rev;
+mod store;
+#[cfg(test)]
+mod testutil;
 mod tree;
+mod walk;
 
-use tree::{GitObject, ObjType};
+use index::IndexEntry;
+use progress::Progress;
+use store::{open_object, open_object_as, read_object, write_object};
+use tree::{normalize_repo_path, GitObject, Ignore, Mode, ObjType};
+use walk::CommitWalk;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Idiot {
+    /// Colorize output, `auto` only colors when stdout is a terminal.
+    #[arg(
+        long,
+        global = true,
+        value_enum,
+        default_value_t,
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "always"
+    )]
+    color: ColorWhen,
+    /// Write loose objects without zlib compression, as plain `<type> <size>\0<content>`
+    /// that `cat`, `less` or `hexdump` can show. Git can't read these objects, only idiot.
+    #[arg(long, global = true)]
+    store: bool,
+    /// Shorten printed SHAs to the shortest unique prefix of at least this many characters.
+    #[arg(
+        long,
+        global = true,
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "7",
+        value_parser = clap::value_parser!(u8).range(4..=40)
+    )]
+    abbrev: Option<u8>,
+    /// Only print the essential output, like the bare SHA from `hash-object` and `write-tree`.
+    #[arg(short, long, global = true)]
+    quiet: bool,
+    /// Print diagnostics to stderr, `info` for what the command does and how long it
+    /// takes, `debug` for every file and object it looks at too.
+    #[arg(long, global = true, value_name = "LEVEL")]
+    log_level: Option<log::Level>,
     #[command(subcommand)]
     command: Command,
 }
 
+/// Write the tree listed by the `ls-tree` lines of `input`, returns its SHA.
+fn mk_tree(input: impl BufRead, store: bool) -> anyhow::Result<String> {
+    let mut objs = vec![];
+    for (idx, line) in input.lines().enumerate() {
+        let line = line?;
+        // The `tree <size> (SHA: <sha>)` line `ls-tree` starts with, entries start with
+        // their mode
+        if line.is_empty() || line.starts_with("tree ") {
+            continue;
+        }
+        objs.push(mktree_entry(&line).with_context(|| format!("line {}", idx + 1))?);
+    }
+    write_tree_entries(objs, store)
+}
+
+/// Write a tree of the entries `objs`, in any order, returns its SHA.
+fn write_tree_entries(mut objs: Vec<GitObject>, store: bool) -> anyhow::Result<String> {
+    objs.sort();
+    let bytes = objs
+        .iter()
+        .map(|o| o.tree_content_bytes())
+        .collect::<anyhow::Result<Vec<_>>>()?
+        .concat();
+    let mut content = format!("tree {}\0", bytes.len()).into_bytes();
+    content.extend_from_slice(&bytes);
+    write_object(&content, store)
+}
+
 #[derive(Subcommand, Debug)]
 #[clap(rename_all = "kebab-case")]
 enum Command {
-    Init,
+    /// Create an empty repository.
+    Init {
+        /// Make a repository without a work tree, laid out in `<directory>` itself
+        /// instead of `<directory>/.idiot`.
+        #[arg(long)]
+        bare: bool,
+        #[arg(default_value = ".")]
+        directory: String,
+    },
+    /// Export the files of a tree (or a commit's tree) as a tar archive, like `git archive`.
+    Archive {
+        /// The archive format, only `tar` for now.
+        #[arg(long, default_value = "tar", value_parser = ["tar"])]
+        format: String,
+        /// Write the archive to this file instead of stdout.
+        #[arg(short, long)]
+        output: Option<String>,
+        /// Put every path under this directory, it must end with `/`.
+        #[arg(long, value_name = "DIR/", default_value = "")]
+        prefix: String,
+        /// The tree, or a commit or tag whose tree to export.
+        tree: String,
+    },
+    /// Print which commit last changed each line of a file at HEAD, as `<short sha> <line>`.
+    ///
+    /// Only first parents are followed and lines are matched naively, moved lines count as
+    /// new.
+    Blame {
+        /// The file's path from the top of the repository.
+        path: String,
+    },
+    /// List the branches, the refs under `refs/heads`, with `*` marking the one HEAD is on,
+    /// create one or delete some.
+    Branch {
+        /// Delete the branches, refusing the one HEAD is on and any not merged into HEAD.
+        #[arg(short, long, requires = "names")]
+        delete: bool,
+        /// Like `-d` but also delete branches that aren't merged.
+        #[arg(short = 'D', requires = "names", conflicts_with = "delete")]
+        force_delete: bool,
+        /// The branch to create and the revision to start it at (HEAD when not given), or
+        /// with `-d` the branches to delete.
+        #[arg(value_name = "NAME")]
+        names: Vec<String>,
+    },
+    /// Print each path that `.gitignore` ignores.
+    ///
+    /// Exits with `0` when any path is ignored and `1` when none are.
+    CheckIgnore {
+        /// Also print the matching rule, as `<source>:<line>:<pattern>\t<path>`.
+        #[arg(short, long)]
+        verbose: bool,
+        /// Match patterns without regard to case, as if `core.ignoreCase` were set.
+        #[arg(long)]
+        ignore_case: bool,
+        #[arg(required = true, value_name = "PATH")]
+        paths: Vec<String>,
+    },
+    /// Print an object's content, type or size.
+    ///
+    /// With `-e` exits with `1` when the object is missing or invalid.
+    #[command(group(
+        ArgGroup::new("query")
+            .required(true)
+            .args(["print", "kind", "size", "exists", "batch_check"])
+    ))]
     CatFile {
+        /// Pretty print the content, trees are listed like `ls-tree`.
         #[arg(short)]
-        print: String,
+        print: bool,
+        /// Print the object's type.
+        #[arg(short = 't')]
+        kind: bool,
+        /// Print the object's size in bytes.
+        #[arg(short)]
+        size: bool,
+        /// Print nothing, only report through the exit code if the object is valid.
+        #[arg(short)]
+        exists: bool,
+        /// With `-t` or `-s`, report whatever type a damaged object's header has instead
+        /// of failing on types that aren't `blob`, `tree`, `commit` or `tag`.
+        #[arg(long, conflicts_with_all = ["print", "exists", "batch_check"])]
+        allow_unknown_type: bool,
+        /// With `-p` and a `<rev>:<path>` object, print the blob through the `textconv`
+        /// command of the path's diff driver (`diff=<driver>` in `.gitattributes`,
+        /// `diff.<driver>.textconv` in `.idiot/config`) when it has one. Only the
+        /// `<rev>:<path>` form has a path to look the driver up by, a blob named by its SHA
+        /// or any other revision is printed as is.
+        #[arg(long, conflicts_with_all = ["kind", "size", "exists", "batch_check"])]
+        textconv: bool,
+        /// Read SHAs from stdin, one per line, printing `<sha> <type> <size>` or
+        /// `<sha> missing` for each.
+        #[arg(long, conflicts_with = "object")]
+        batch_check: bool,
+        /// With `--batch-check`, read the SHAs from this file instead of stdin.
+        #[arg(long, value_name = "PATH", conflicts_with_all = ["print", "kind", "size", "exists"])]
+        batch_file: Option<String>,
+        /// With `--batch-check`, list every loose object in the store in SHA order instead
+        /// of reading SHAs.
+        #[arg(long, conflicts_with_all = ["print", "kind", "size", "exists", "batch_file"])]
+        batch_all_objects: bool,
+        /// Also print to stderr where the object is stored and its compressed size on disk.
+        #[arg(long, conflicts_with = "batch_check")]
+        report_storage: bool,
+        /// Read the object from this loose object file instead of looking it up by SHA,
+        /// for objects recovered outside the store.
+        #[arg(
+            long,
+            value_name = "PATH",
+            conflicts_with_all = ["object", "batch_check", "textconv", "report_storage"]
+        )]
+        raw_file: Option<String>,
+        /// Follow symlinks in a `<rev>:<path>` object to what they point at in the same tree,
+        /// instead of reading the link itself.
+        #[arg(long, conflicts_with = "raw_file")]
+        follow_symlinks: bool,
+        /// The SHA of the object, a revision like `HEAD` or `v1.0^{commit}`, or
+        /// `<rev>:<path>` for the file or directory at `path` in that revision's tree.
+        #[arg(required_unless_present_any = ["batch_check", "raw_file"])]
+        object: Option<String>,
+    },
+    /// List the untracked files, the ones in the work tree but not in the index, and with
+    /// `-f` delete them.
+    ///
+    /// Unlike git files in untracked directories are listed one by one, and directories
+    /// are only removed once they are empty. Nested repositories are never touched.
+    Clean {
+        /// Delete the files, without it they are only listed.
+        #[arg(short, long)]
+        force: bool,
+        /// Only list the files even with `-f`.
+        #[arg(short = 'n', long)]
+        dry_run: bool,
+        /// Delete ignored files too, `.git/` and `.idiot/` are always kept.
+        #[arg(short = 'x')]
+        ignored: bool,
+    },
+    /// Copy the repository at the local path `<source>` into `<dest>` with its history,
+    /// branches (as `origin/<branch>`) and tags, then check out the branch its HEAD is on.
+    Clone {
+        /// Make a bare repository in `<dest>` with the branches as they are and no work
+        /// tree, for a mirror.
+        #[arg(long)]
+        bare: bool,
+        source: String,
+        dest: String,
+    },
+    /// Name a commit after the nearest tag it descends from, as `<tag>-<n>-g<short sha>`
+    /// where `n` is how many commits it is past the tag, or just `<tag>` on the tag itself.
+    Describe {
+        /// Print the short SHA when no tag can be reached instead of failing.
+        #[arg(long)]
+        always: bool,
+        /// The commit to describe.
+        #[arg(default_value = "HEAD")]
+        rev: String,
     },
+    /// Download the branches of the remote `<remote>` (see `remote add`) and every object
+    /// they need, updating its remote-tracking branches under `refs/remotes/<remote>`.
+    ///
+    /// Only http(s) remotes over git's dumb protocol are supported, where the server just
+    /// serves the repository's files. Tags aren't fetched.
+    Fetch { remote: String },
+    /// Print a line per ref under `.idiot/refs`, filling in `--format`'s `%(<field>)`s.
+    ///
+    /// The fields are `refname`, `refname:short`, `objectname`, `objectname:short` and
+    /// `objecttype`, `%%` is a literal `%`.
+    ForEachRef {
+        #[arg(long, default_value = "%(objectname) %(objecttype)\t%(refname)")]
+        format: String,
+        /// Only show refs starting with one of these, like `refs/tags`.
+        #[arg(value_name = "PATTERN")]
+        patterns: Vec<String>,
+    },
+    /// Compute the blob SHA of files, like `git hash-object`.
+    ///
+    /// The paths are positional and `-w` is the write toggle. Nothing is written to the
+    /// object store unless `-w` is given, the old `-w <path>` form always wrote.
     HashObject {
-        #[arg(short)]
-        which: String,
+        /// Write the objects into the object store, otherwise only print the hashes.
+        #[arg(short, long)]
+        write: bool,
+        /// The files to hash, one SHA is printed per file in the same order.
+        #[arg(required_unless_present = "stdin_paths", value_name = "FILE")]
+        files: Vec<String>,
+        /// Read the files to hash from stdin, one path per line, after any given as
+        /// arguments.
+        #[arg(long)]
+        stdin_paths: bool,
+        /// With `--stdin-paths`, paths are separated by NULs instead of newlines.
+        #[arg(short = 'z', requires = "stdin_paths")]
+        null: bool,
+        /// Prefix each hash with `SHA: `.
+        #[arg(short, long)]
+        verbose: bool,
+        /// Print the compressed loose object instead of the hash, byte for byte what is
+        /// (or with `--write` was) written to the store.
+        #[arg(long)]
+        stdout: bool,
+    },
+    /// List the paths in the index, which can be one written by git.
+    LsFiles {
+        /// Print `<mode> <sha> <stage>\t<path>` instead of only the path.
+        #[arg(short, long)]
+        stage: bool,
+        /// End each line with a NUL instead of a newline, for paths with odd characters.
+        #[arg(short = 'z')]
+        null: bool,
     },
     LsTree {
         /// Prints out only the file name. Default is `true`.
         #[arg(long)]
         name_only: bool,
-        /// The sha1 of your tree.
+        /// Recurse into subtrees, listing every blob with its full path.
+        #[arg(short)]
+        recursive: bool,
+        /// With `-r`, only list entries under `<dir>` and print their paths relative to it.
+        #[arg(long, value_name = "DIR", requires = "recursive")]
+        relative: Option<String>,
+        /// End each entry with a NUL instead of a newline and leave out the `tree` header
+        /// line, for paths with odd characters.
+        #[arg(short = 'z')]
+        null: bool,
+        /// With `--name-only`, end the names of subtrees with `/` to tell them from files.
+        #[arg(long, requires = "name_only")]
+        directories_marked: bool,
+        /// Only list trees, with `-r` every tree below the top one (git's `-d`).
+        #[arg(short = 'd', long, conflicts_with = "blobs_only")]
+        trees_only: bool,
+        /// Only list blobs, the files and symlinks, leaving out trees and gitlinks.
+        #[arg(long)]
+        blobs_only: bool,
+        /// Follow each gitlink to its submodule when `.gitmodules` has it and it is checked
+        /// out in the work tree, and print the summary of the commit it points at after the
+        /// path.
+        #[arg(long)]
+        dereference_gitlinks: bool,
+        /// The sha1 of your tree, or a commit, tag or any revision that peels to a tree.
         tree_sha: String,
     },
-    WriteTree,
+    /// Pack the objects whose SHAs (or revisions) are read from stdin, one per line, into
+    /// `.idiot/objects/pack/pack-<sha>.pack` with its `.idx` and print the pack's `<sha>`.
+    ///
+    /// Objects are stored whole, there is no delta compression.
+    PackObjects {
+        /// Write only the pack, to stdout.
+        #[arg(long)]
+        stdout: bool,
+    },
+    /// Move every branch and tag into `.idiot/packed-refs` and delete their loose files,
+    /// like `git pack-refs --all`, HEAD and other symbolic refs stay as they are.
+    PackRefs,
+    /// Build a tree from `<mode> <type> <sha>\t<name>` lines (what `ls-tree` prints) read
+    /// from stdin, write it and print its SHA.
+    MkTree,
+    /// List the commits reachable from a revision, nearest first, one SHA per line.
+    RevList {
+        /// Stop after this many commits.
+        #[arg(short = 'n', long)]
+        max_count: Option<usize>,
+        /// Only follow the first parent of merges.
+        #[arg(long)]
+        first_parent: bool,
+        rev: String,
+    },
+    /// List the remotes recorded in `.idiot/config`, or add one.
+    Remote {
+        /// Also print each remote's URL, as `<name>\t<url> (fetch)` and `(push)` lines.
+        #[arg(short, long)]
+        verbose: bool,
+        #[command(subcommand)]
+        action: Option<RemoteAction>,
+    },
+    /// Move the current branch (or a detached HEAD) to a commit.
+    ///
+    /// `--mixed`, the default, also resets the index to the commit's tree and `--hard` the
+    /// work tree too, overwriting tracked files and deleting those the commit doesn't have.
+    #[command(group(ArgGroup::new("mode").args(["soft", "mixed", "hard"])))]
+    Reset {
+        /// Only move the branch, the index and work tree are left alone.
+        #[arg(long)]
+        soft: bool,
+        /// Move the branch and reset the index, the work tree is left alone.
+        #[arg(long)]
+        mixed: bool,
+        /// Move the branch and reset both the index and the work tree.
+        #[arg(long)]
+        hard: bool,
+        /// Print what would change instead of changing anything.
+        #[arg(long)]
+        dry_run: bool,
+        /// The commit to reset to.
+        #[arg(default_value = "HEAD")]
+        target: String,
+    },
+    /// Resolve revisions (`HEAD`, branch and tag names, full or short SHAs) to full SHAs,
+    /// one per line.
+    RevParse {
+        /// Skip revisions that don't resolve instead of failing on them.
+        #[arg(long)]
+        ignore_missing: bool,
+        /// Print the short name of the branch or tag each revision names (`master` for
+        /// `HEAD` on that branch) instead of its SHA, `HEAD` stays `HEAD` when detached.
+        #[arg(long)]
+        abbrev_ref: bool,
+        #[arg(required = true, value_name = "REV")]
+        revs: Vec<String>,
+    },
+    /// Write every object of a packfile (like one from `pack-objects` or git) into the
+    /// object store as loose objects.
+    UnpackObjects {
+        /// The pack to read, stdin when not given.
+        pack: Option<String>,
+    },
+    /// Point a ref at an object, through the ref it points at when it is symbolic like
+    /// `HEAD` on a branch.
+    UpdateRef {
+        /// Print `<ref>: <old> -> <new>` instead of changing anything, `(none)` for a ref
+        /// that doesn't exist yet and `(deleted)` for one that is deleted.
+        #[arg(long)]
+        dry_run: bool,
+        /// Read `update <ref> <new> [<old>]` and `delete <ref> [<old>]` lines from stdin
+        /// and apply them all, each is checked before any ref changes.
+        #[arg(long, conflicts_with_all = ["name", "new", "old"])]
+        stdin: bool,
+        /// Delete the ref, its loose file and its `packed-refs` line, as `-d <ref> [<old>]`.
+        #[arg(short, long, conflicts_with_all = ["stdin", "old"])]
+        delete: bool,
+        /// The ref, `HEAD` or a full name like `refs/heads/master`.
+        #[arg(value_name = "REF", required_unless_present = "stdin")]
+        name: Option<String>,
+        /// The revision to point it at, with `-d` the SHA it must still be at.
+        #[arg(value_name = "NEW", required_unless_present_any = ["stdin", "delete"])]
+        new: Option<String>,
+        /// Only update the ref if it still points here, 40 zeros for a ref that must not
+        /// exist yet.
+        #[arg(value_name = "OLD")]
+        old: Option<String>,
+    },
+    /// List HEAD and every ref under `.idiot/refs` as `<sha> <refname>`.
+    ///
+    /// Exits with `1` when there is no ref to show.
+    ShowRef {
+        /// Only show branches, `refs/heads`.
+        #[arg(long)]
+        heads: bool,
+        /// Only show tags, `refs/tags`.
+        #[arg(long)]
+        tags: bool,
+    },
+    /// Check that HEAD's commit, its tree and every object below it exist and hash correctly.
+    ///
+    /// Exits with `1` at the first broken object, `128` if HEAD can't be resolved.
+    VerifyRepo,
+    /// Write the work tree, or with `--index` the index, as a tree object and print its SHA.
+    WriteTree {
+        /// Write the tree of `.idiot/index` (like git, or an index git wrote) instead of
+        /// hashing the work tree, each blob it names has to be in the store.
+        #[arg(long, conflicts_with_all = ["no_gitignore", "recurse_submodules", "progress"])]
+        index: bool,
+        /// With `--index`, write the tree even when entries name blobs missing from the
+        /// store.
+        #[arg(long, requires = "index")]
+        missing_ok: bool,
+        /// Also hex dump the uncompressed tree object (`tree <size>\0...`) to stderr.
+        #[arg(long)]
+        dump_bytes: bool,
+        /// Print the tree's size and entries instead of only its SHA.
+        #[arg(short, long)]
+        verbose: bool,
+        /// Show a count of the files hashed on stderr even when it isn't a terminal, on
+        /// a terminal it shows up by itself when hashing takes a while.
+        #[arg(long)]
+        progress: bool,
+        /// Snapshot ignored files too, only `.git/` and `.idiot/` are left out.
+        #[arg(long)]
+        no_gitignore: bool,
+        /// Snapshot the files of nested repositories as ordinary subtrees instead of
+        /// recording a gitlink to their HEAD commit. Unlike git, for exporting a monorepo.
+        #[arg(long)]
+        recurse_submodules: bool,
+    },
 }
 
+/// The repository directory at the top of a work tree.
 const IDIOT: &str = ".idiot";
-const OBJS: &str = ".idiot/objects";
-const REFS: &str = ".idiot/refs";
-const HEAD: &str = ".idiot/HEAD";
 
 /// Un-compress a Zlib Encoded vector of bytes and returns a Vec<u8> or error
 fn decomp_obj(bytes: &[u8]) -> io::Result<Vec<u8>> {
     let mut s = vec![];
-    ZlibDecoder::new(bytes).read_to_end(&mut s)?;
+    decomp_to(bytes, &mut s)?;
     Ok(s)
 }
 /// Compress a vector of bytes and returns a Vec<u8> or error
-fn compress_obj(bytes: &[u8]) -> io::Result<Vec<u8>> {
+fn compress_obj(bytes: &[u8], store: bool) -> io::Result<Vec<u8>> {
     let mut s = vec![];
-    ZlibEncoder::new(bytes, Compression::default()).read_to_end(&mut s)?;
+    compress_from(bytes, &mut s, store)?;
     Ok(s)
 }
 
-fn main() -> anyhow::Result<()> {
-    let args = Idiot::parse();
+/// Un-compress the Zlib encoded `input` straight into `out`, returns the number of bytes written.
+///
+/// Decoding stops once the zlib stream reports its end, so any padding other tools
+/// append after a complete stream is ignored rather than treated as corrupt data.
+/// Objects written with `--store` are not a zlib stream and are copied as is.
+fn decomp_to<R: BufRead, W: Write>(mut input: R, out: &mut W) -> io::Result<u64> {
+    if is_zlib(input.fill_buf()?) {
+        io::copy(&mut ZlibDecoder::new(input), out)
+    } else {
+        io::copy(&mut input, out)
+    }
+}
+/// Objects at least this big have their start test compressed first, when it doesn't
+/// shrink (media, archives) they are written with compression level 0.
+const INCOMPRESSIBLE_THRESHOLD: usize = 64 * 1024;
+
+/// Compress everything read from `input` into `out`, returns the number of compressed bytes.
+///
+/// When `store` is set the bytes are copied without any zlib framing. Large input that
+/// doesn't compress is still a zlib stream, only without spending time deflating it.
+fn compress_from<R: Read, W: Write>(input: R, out: &mut W, store: bool) -> io::Result<u64> {
+    if store {
+        return io::copy(&mut BufReader::new(input), out);
+    }
+    let mut sample = Vec::with_capacity(INCOMPRESSIBLE_THRESHOLD);
+    let mut input = input;
+    (&mut input)
+        .take(INCOMPRESSIBLE_THRESHOLD as u64)
+        .read_to_end(&mut sample)?;
+
+    let mut level = Compression::default();
+    if sample.len() == INCOMPRESSIBLE_THRESHOLD {
+        let mut test = vec![];
+        ZlibEncoder::new(sample.as_slice(), Compression::fast()).read_to_end(&mut test)?;
+        // Less than 1/16th saved isn't worth deflating the rest for
+        if test.len() > sample.len() - sample.len() / 16 {
+            level = Compression::none();
+        }
+    }
+    io::copy(
+        &mut ZlibEncoder::new(BufReader::new(sample.as_slice().chain(input)), level),
+        out,
+    )
+}
+
+/// Checks for a zlib stream header (RFC 1950), deflate method and a valid check value.
+///
+/// Uncompressed objects start with their type name so they can never look like one.
+fn is_zlib(bytes: &[u8]) -> bool {
+    match bytes {
+        [cmf, flg, ..] => cmf & 0x0f == 8 && (u16::from(*cmf) << 8 | u16::from(*flg)) % 31 == 0,
+        _ => false,
+    }
+}
+
+/// Reads from `inner` while feeding the same bytes to a SHA1 hasher.
+struct HashReader<R> {
+    inner: R,
+    hasher: Sha1,
+}
+
+impl<R: Read> Read for HashReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+/// Parse a `<type> <size>\0` object header.
+fn parse_header(header: &[u8]) -> anyhow::Result<(String, usize)> {
+    parse_header_as(header, false)
+}
+
+/// Parse a `<type> <size>\0` object header, only checking the type is one git knows
+/// without `allow_unknown_type`.
+fn parse_header_as(header: &[u8], allow_unknown_type: bool) -> anyhow::Result<(String, usize)> {
+    let header = header
+        .strip_suffix(b"\0")
+        .context("no '\\0' after the header")?;
+    let header = String::from_utf8_lossy(header);
+    let (kind, size) = header
+        .split_once(' ')
+        .with_context(|| format!("invalid header '{}'", header))?;
+    anyhow::ensure!(
+        allow_unknown_type || matches!(kind, "blob" | "tree" | "commit" | "tag"),
+        "unknown object type '{}'",
+        kind
+    );
+    let size = size
+        .parse()
+        .with_context(|| format!("invalid size '{}'", size))?;
+    Ok((kind.to_string(), size))
+}
+
+/// Read the object `sha` and check its content hashes back to `sha` and its header
+/// size is correct, returns the decompressed object.
+fn verify_object(sha: &str) -> anyhow::Result<Vec<u8>> {
+    let obj = read_object(sha)?;
+    let actual = hex::encode(Sha1::digest(&obj));
+    anyhow::ensure!(actual == sha, "object {} hashes to {}", sha, actual);
+
+    let nul = obj
+        .iter()
+        .position(|ch| ch == &b'\0')
+        .with_context(|| format!("object {} has no header", sha))?;
+    let (_, size) = parse_header(&obj[..=nul])?;
+    anyhow::ensure!(
+        size == obj.len() - nul - 1,
+        "object {} is {} bytes but its header says {}",
+        sha,
+        obj.len() - nul - 1,
+        size
+    );
+    Ok(obj)
+}
+
+/// Verify the tree `sha` found at `path` and everything below it, returns how many
+/// objects were checked.
+fn verify_tree(sha: &str, path: &str) -> anyhow::Result<usize> {
+    let obj = verify_object(sha).with_context(|| format!("broken tree {} at '{}'", sha, path))?;
+    let tree = GitObject::try_from_bytes(&obj)
+        .with_context(|| format!("broken tree {} at '{}'", sha, path))?;
+    let ObjType::Tree { objs, .. } = tree.obj_type else {
+        anyhow::bail!("object {} at '{}' is not a tree", sha, path)
+    };
+
+    let mut count = 1;
+    for o in &objs {
+        let sha = hex::encode(o.sha.as_ref().expect("tree entries have a sha"));
+        let path = format!("{}{}", path, o.as_path_str());
+        match o.mode {
+            Mode::SubDir => count += verify_tree(&sha, &format!("{}/", path))?,
+            // The commit lives in the submodule's repository, not ours
+            Mode::SubMod => {}
+            Mode::FileBlob | Mode::ExeBlob | Mode::SymLink => {
+                verify_object(&sha)
+                    .with_context(|| format!("broken blob {} at '{}'", sha, path))?;
+                count += 1;
+            }
+        }
+    }
+    Ok(count)
+}
+
+/// Push every non-tree entry of `objs` and the trees below them onto `out`, each with its
+/// path from the top tree (`prefix` is the path of the tree `objs` came from).
+///
+/// With `trees` each subtree is pushed as well, just before its entries.
+fn flatten_tree(
+    objs: Vec<GitObject>,
+    prefix: &str,
+    trees: bool,
+    out: &mut Vec<GitObject>,
+) -> anyhow::Result<()> {
+    for o in objs {
+        let path = format!("{}{}", prefix, o.as_path_str());
+        if let Mode::SubDir = o.mode {
+            let sha = hex::encode(o.sha.as_ref().expect("tree entries have a sha"));
+            if trees {
+                out.push(GitObject {
+                    obj_type: ObjType::Blob {
+                        path: path.clone(),
+                        content: vec![],
+                    },
+                    mode: o.mode,
+                    sha: o.sha.clone(),
+                });
+            }
+            let ObjType::Tree { objs, .. } =
+                GitObject::try_from_bytes(&read_object(&sha)?)?.obj_type
+            else {
+                anyhow::bail!("object {} at '{}' is not a tree", sha, path)
+            };
+            flatten_tree(objs, &format!("{}/", path), trees, out)?;
+        } else {
+            out.push(GitObject {
+                obj_type: ObjType::Blob {
+                    path,
+                    content: vec![],
+                },
+                ..o
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Write the file, symlink or (empty, for a gitlink) directory `entry` into the work tree
+/// at `top` at its path, returns the modification time and size the index records for it.
+fn checkout_entry(top: &Path, entry: &GitObject) -> anyhow::Result<((u32, u32), u32)> {
+    let path = &top.join(entry.as_path_str());
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    // Writing through a symlink that is in the way would change the file it points at
+    match fs::symlink_metadata(path) {
+        Ok(meta) if meta.is_dir() => {
+            if !matches!(entry.mode, Mode::SubMod) {
+                fs::remove_dir(path).with_context(|| {
+                    format!("'{}' is a directory that isn't empty", path.display())
+                })?
+            }
+        }
+        Ok(_) => fs::remove_file(path)?,
+        Err(_) => {}
+    }
+
+    let sha = hex::encode(entry.sha.as_ref().expect("tree entries have a sha"));
+    let content = || store::read_blob(&sha);
+    match entry.mode {
+        Mode::SubMod => {
+            fs::create_dir_all(path)?;
+            return Ok(((0, 0), 0));
+        }
+        Mode::SymLink => {
+            let target = String::from_utf8(content()?).context("symlink target isn't UTF-8")?;
+            std::os::unix::fs::symlink(target, path)?;
+        }
+        Mode::FileBlob | Mode::ExeBlob => {
+            fs::write(path, content()?)
+                .with_context(|| format!("failed to write to {}", path.display()))?;
+            if let Mode::ExeBlob = entry.mode {
+                fs::set_permissions(path, fs::Permissions::from_mode(0o755))?;
+            }
+        }
+        Mode::SubDir => anyhow::bail!("can't check out the tree '{}' as a file", path.display()),
+    }
+    let meta = fs::symlink_metadata(path)?;
+    let mtime = meta.modified()?.duration_since(UNIX_EPOCH)?;
+    Ok((
+        (mtime.as_secs() as u32, mtime.subsec_nanos()),
+        meta.len() as u32,
+    ))
+}
+
+/// Lay out an empty repository in `dir`, its `.idiot` or the bare repository itself.
+fn init_repo(dir: &Path) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        !dir.join("HEAD").exists(),
+        "'{}' is already a repository",
+        dir.display()
+    );
+    fs::create_dir_all(dir.join("objects"))?;
+    fs::create_dir_all(dir.join("refs"))?;
+    fs::write(dir.join("HEAD"), "ref: refs/heads/master\n")?;
+    Ok(())
+}
+
+/// Make the index match the tree of `commit`, with `work_tree` (the top of one) the
+/// tracked files in it too.
+///
+/// Every path is checked before anything is written, and files that are no longer
+/// tracked are removed before the new ones are written so a file can become a directory
+/// and back.
+fn reset_index(commit: &str, work_tree: Option<&Path>) -> anyhow::Result<()> {
+    let tree = rev::peel(commit, Some("tree"))?;
+    let ObjType::Tree { objs, .. } = GitObject::try_from_bytes(&read_object(&tree)?)?.obj_type
+    else {
+        anyhow::bail!("object {} is not a tree", tree)
+    };
+    let mut files = vec![];
+    flatten_tree(objs, "", false, &mut files)?;
+    // A path that is a file and a directory at once would write through the first
+    let paths = files
+        .iter()
+        .map(|f| f.as_path_str())
+        .collect::<HashSet<_>>();
+    for path in &paths {
+        tree::check_entry_path(path)?;
+        for (at, _) in path.match_indices('/') {
+            anyhow::ensure!(
+                !paths.contains(&path[..at]),
+                "'{}' is both a file and a directory in tree {}",
+                &path[..at],
+                tree
+            );
+        }
+    }
+
+    if let Some(top) = work_tree {
+        // Only what was tracked goes, untracked files are never touched
+        let stale = index::read_index()?
+            .into_iter()
+            .map(|e| e.path)
+            .filter(|path| !paths.contains(path.as_str()))
+            .collect::<HashSet<_>>();
+        // An untracked file where a new directory goes would stop the checkout halfway
+        for file in &files {
+            let path = file.as_path_str();
+            for (at, _) in path.match_indices('/') {
+                let dir = &path[..at];
+                let in_the_way = fs::symlink_metadata(top.join(dir)).is_ok_and(|m| !m.is_dir());
+                anyhow::ensure!(
+                    !in_the_way || stale.contains(dir),
+                    "untracked file '{}' is in the way of '{}'",
+                    dir,
+                    path
+                );
+            }
+        }
+        for path in &stale {
+            remove_work_tree_file(top, path)?;
+        }
+    }
+
+    let mut entries = vec![];
+    for file in files {
+        let (mtime, size) = match work_tree {
+            Some(top) => checkout_entry(top, &file)?,
+            None => ((0, 0), 0),
+        };
+        entries.push(IndexEntry {
+            mode: file.mode,
+            sha: file
+                .sha
+                .as_deref()
+                .and_then(|sha| sha.try_into().ok())
+                .context("tree entry has no sha")?,
+            stage: 0,
+            mtime,
+            size,
+            path: file.as_path_str().to_string(),
+        });
+    }
+    entries.sort_by(|a, b| a.path.cmp(&b.path));
+    index::write_index(&entries)
+}
+
+/// Write the tree of the index `entries` under `dir` (`""` or ending with `/`), its subtrees
+/// included, returns its SHA. The entries are sorted by path, as the index keeps them.
+///
+/// Unless `missing_ok` every blob has to be in the store, like git a gitlink's commit is
+/// never looked for.
+fn write_index_tree(
+    entries: &[IndexEntry],
+    dir: &str,
+    missing_ok: bool,
+    store: bool,
+) -> anyhow::Result<String> {
+    let mut objs = vec![];
+    let mut rest = entries;
+    while let Some(entry) = rest.first() {
+        anyhow::ensure!(entry.stage == 0, "'{}' has merge conflicts", entry.path);
+        let (mode, name, sha, len) = match entry.path[dir.len()..].split_once('/') {
+            Some((sub, _)) => {
+                let sub_dir = format!("{}{}/", dir, sub);
+                let len = rest
+                    .iter()
+                    .take_while(|e| e.path.starts_with(&sub_dir))
+                    .count();
+                let sha = write_index_tree(&rest[..len], &sub_dir, missing_ok, store)?;
+                (Mode::SubDir, sub, hex::decode(sha)?, len)
+            }
+            None => {
+                let sha = hex::encode(entry.sha);
+                let kind = open_object(&sha).map(|(kind, ..)| kind).ok();
+                anyhow::ensure!(
+                    missing_ok
+                        || matches!(entry.mode, Mode::SubMod)
+                        || kind.as_deref() == Some(entry.mode.obj_name()),
+                    "invalid object {} {} for '{}', --missing-ok writes the tree anyway",
+                    entry.mode as usize,
+                    sha,
+                    entry.path
+                );
+                (entry.mode, &entry.path[dir.len()..], entry.sha.to_vec(), 1)
+            }
+        };
+        tree::check_entry_name(name).with_context(|| format!("in '{}'", entry.path))?;
+        objs.push(GitObject {
+            mode,
+            obj_type: ObjType::Blob {
+                path: name.to_string(),
+                content: vec![],
+            },
+            sha: Some(sha),
+        });
+        rest = &rest[len..];
+    }
+    write_tree_entries(objs, store)
+}
+
+/// Add the files under `dir` (`""` or ending with `/`) of the work tree at `top` that
+/// aren't in `tracked` to `files`, as `/` separated paths from the top.
+///
+/// Ignored files and directories are left out, nested repositories aren't looked into.
+fn untracked_files(
+    top: &Path,
+    dir: &str,
+    ignore: &Ignore,
+    tracked: &HashSet<String>,
+    files: &mut Vec<String>,
+) -> anyhow::Result<()> {
+    for entry in fs::read_dir(top.join(dir))? {
+        let entry = entry?;
+        let path = format!("{}{}", dir, entry.file_name().to_string_lossy());
+        // Symlinks are removed as files, never followed
+        let is_dir = entry.file_type()?.is_dir();
+        let mark = if is_dir { "/" } else { "" };
+        if ignore.is_ignored(Path::new(&format!("{}{}", path, mark))) || tracked.contains(&path) {
+            continue;
+        }
+        if !is_dir {
+            files.push(path);
+        } else if tree::submodule_repo(&entry.path())?.is_none() {
+            untracked_files(top, &format!("{}/", path), ignore, tracked, files)?;
+        }
+    }
+    Ok(())
+}
+
+/// Delete the tracked file at `path` in the work tree at `top` and any directories it
+/// leaves empty.
+fn remove_work_tree_file(top: &Path, path: &str) -> anyhow::Result<()> {
+    match fs::remove_file(top.join(path)) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e).with_context(|| format!("removing '{}'", path)),
+    }
+    let mut dir = Path::new(path).parent();
+    while let Some(d) = dir.filter(|d| !d.as_os_str().is_empty()) {
+        if fs::remove_dir(top.join(d)).is_err() {
+            break;
+        }
+        dir = d.parent();
+    }
+    Ok(())
+}
+
+/// Parse one `<mode> <type> <sha>\t<name>` line of `mktree` input.
+///
+/// A space is accepted in place of the tab so our own `ls-tree` output round trips.
+fn mktree_entry(line: &str) -> anyhow::Result<GitObject> {
+    let mut split = line.splitn(4, [' ', '\t']);
+    let (Some(mode), Some(kind), Some(sha), Some(name)) =
+        (split.next(), split.next(), split.next(), split.next())
+    else {
+        anyhow::bail!("expected '<mode> <type> <sha>\\t<name>'")
+    };
+
+    let mode = mode
+        .parse()
+        .ok()
+        .and_then(Mode::try_new)
+        .with_context(|| format!("invalid mode '{}'", mode))?;
+    anyhow::ensure!(
+        kind == mode.obj_name(),
+        "mode {} is a {} not a {}",
+        mode as usize,
+        mode.obj_name(),
+        kind
+    );
+    let sha = hex::decode(sha)
+        .ok()
+        .filter(|sha| sha.len() == 20)
+        .with_context(|| format!("invalid sha '{}'", sha))?;
+    tree::check_entry_name(name)?;
+
+    Ok(GitObject {
+        mode,
+        obj_type: ObjType::Blob {
+            path: name.to_string(),
+            content: vec![],
+        },
+        sha: Some(sha),
+    })
+}
+
+#[derive(Subcommand, Debug)]
+enum RemoteAction {
+    /// Record the remote `<name>` at `<url>`, fetching its branches into
+    /// `refs/remotes/<name>`.
+    Add { name: String, url: String },
+}
+
+/// Fail unless `url` looks like something a remote can be fetched from, a URL with a
+/// scheme git knows, `host:path` or a local path.
+fn check_remote_url(url: &str) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        !url.is_empty() && !url.contains(|ch: char| ch.is_whitespace() || ch.is_control()),
+        "'{}' is not a valid URL",
+        url
+    );
+    if let Some((scheme, rest)) = url.split_once("://") {
+        anyhow::ensure!(
+            ["http", "https", "git", "ssh", "file"].contains(&scheme),
+            "URL scheme '{}' is not supported",
+            scheme
+        );
+        anyhow::ensure!(!rest.is_empty(), "'{}' is not a valid URL", url);
+    }
+    Ok(())
+}
+
+/// One change to a ref for `update-ref`, `new` is `None` to delete it.
+struct RefUpdate {
+    name: String,
+    new: Option<String>,
+    old: Option<String>,
+}
+
+/// Parse one `update <ref> <new> [<old>]` or `delete <ref> [<old>]` line of
+/// `update-ref --stdin` input.
+fn ref_update(line: &str) -> anyhow::Result<RefUpdate> {
+    let words = line.split_whitespace().collect::<Vec<_>>();
+    let (name, new, old) = match words.as_slice() {
+        ["update", name, new] => (name, Some(new), None),
+        ["update", name, new, old] => (name, Some(new), Some(old)),
+        ["delete", name] => (name, None, None),
+        ["delete", name, old] => (name, None, Some(old)),
+        _ => anyhow::bail!("expected 'update <ref> <new> [<old>]' or 'delete <ref> [<old>]'"),
+    };
+    Ok(RefUpdate {
+        name: name.to_string(),
+        new: new.map(|new| rev::resolve(new)).transpose()?,
+        old: old_value(old.map(|old| old.to_string()))?,
+    })
+}
+
+/// Resolve the `<old>` value of `update-ref`, all zeros (for a ref that must not exist)
+/// is kept as is.
+fn old_value(old: Option<String>) -> anyhow::Result<Option<String>> {
+    Ok(match old {
+        Some(old) if !old.is_empty() && old.bytes().all(|ch| ch == b'0') => Some(old),
+        Some(old) => Some(rev::resolve(&old)?),
+        None => None,
+    })
+}
+
+/// When `commit` was committed, in seconds since the epoch.
+fn commit_time(commit: &str) -> anyhow::Result<u64> {
+    let ObjType::Commit { headers, .. } =
+        GitObject::try_from_bytes(&read_object(commit)?)?.obj_type
+    else {
+        anyhow::bail!("{} is not a commit", commit)
+    };
+    // `committer <name> <<email>> <seconds> <timezone>`
+    headers
+        .iter()
+        .find(|(key, _)| key == "committer")
+        .and_then(|(_, value)| value.rsplit(' ').nth(1)?.parse().ok())
+        .with_context(|| format!("commit {} has no committer time", commit))
+}
+
+/// Whether the commit `ancestor` is `commit` or in its history.
+fn is_ancestor(ancestor: &str, commit: &str) -> anyhow::Result<bool> {
+    for c in CommitWalk::new(commit) {
+        if c?.sha == ancestor {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Find the tag closest to the commit `sha` walking back through its parents, returns the
+/// tag's short name and how many commits `sha` has that the tag doesn't, like git counts.
+fn nearest_tag(sha: &str) -> anyhow::Result<Option<(String, usize)>> {
+    let mut tags = HashMap::new();
+    for (name, tag_sha) in rev::list_refs("refs/tags")? {
+        // Tags of trees or blobs can never be reached from a commit
+        if let Ok(commit) = rev::peel(&tag_sha, Some("commit")) {
+            let name = name.trim_start_matches("refs/tags/").to_string();
+            tags.entry(commit).or_insert(name);
+        }
+    }
+
+    // Breadth first, so the first tag found is the closest one
+    for commit in CommitWalk::new(sha) {
+        let commit = commit?;
+        if let Some(name) = tags.get(&commit.sha) {
+            let tagged = CommitWalk::new(&commit.sha)
+                .map(|c| c.map(|c| c.sha))
+                .collect::<anyhow::Result<HashSet<_>>>()?;
+            let mut dist = 0;
+            for c in CommitWalk::new(sha) {
+                dist += usize::from(!tagged.contains(&c?.sha));
+            }
+            return Ok(Some((name.clone(), dist)));
+        }
+    }
+    Ok(None)
+}
+
+/// The fields `for-each-ref --format` knows.
+const REF_FIELDS: [&str; 5] = [
+    "refname",
+    "refname:short",
+    "objectname",
+    "objectname:short",
+    "objecttype",
+];
+
+/// A piece of a `for-each-ref` format.
+enum FormatPart {
+    Text(String),
+    Field(&'static str),
+}
+
+/// Split a `for-each-ref` format into literal text and the fields to fill in.
+fn parse_ref_format(format: &str) -> anyhow::Result<Vec<FormatPart>> {
+    let mut parts = vec![];
+    let mut text = String::new();
+    let mut rest = format;
+    while let Some(at) = rest.find('%') {
+        text.push_str(&rest[..at]);
+        rest = &rest[at + 1..];
+        if let Some(after) = rest.strip_prefix('%') {
+            text.push('%');
+            rest = after;
+            continue;
+        }
+        let (field, after) = rest
+            .strip_prefix('(')
+            .and_then(|field| field.split_once(')'))
+            .with_context(|| format!("bad format '{}', expected '%(<field>)'", format))?;
+        let field = REF_FIELDS
+            .into_iter()
+            .find(|known| *known == field)
+            .with_context(|| format!("unknown field name '{}'", field))?;
+        parts.push(FormatPart::Text(std::mem::take(&mut text)));
+        parts.push(FormatPart::Field(field));
+        rest = after;
+    }
+    text.push_str(rest);
+    parts.push(FormatPart::Text(text));
+    Ok(parts)
+}
+
+/// The textconv command configured for the path of a `<rev>:<path>` object name.
+fn textconv_command(object: &str) -> Option<String> {
+    let (_, path) = object.split_once(':')?;
+    let driver = config::attribute(path.trim_start_matches("./"), "diff")?;
+    config::Config::read()
+        .get(&format!("diff.{}.textconv", driver))
+        .map(str::to_string)
+}
+
+/// Run the shell `command` on a temp file holding `content` like git does, returns what
+/// it prints.
+fn run_textconv(command: &str, mut content: impl Read) -> anyhow::Result<Vec<u8>> {
+    let tmp_path = std::env::temp_dir().join(format!("idiot_textconv_{}", std::process::id()));
+    let mut tmp = fs::File::create(&tmp_path)
+        .with_context(|| format!("failed to write to {}", tmp_path.display()))?;
+    io::copy(&mut content, &mut tmp).context("uncompressing object")?;
+    drop(tmp);
+
+    let output = process::Command::new("sh")
+        .arg("-c")
+        .arg(format!("{} \"$1\"", command))
+        .arg(command)
+        .arg(&tmp_path)
+        .stderr(process::Stdio::inherit())
+        .output();
+    fs::remove_file(&tmp_path)?;
+    let output = output.with_context(|| format!("running textconv '{}'", command))?;
+    anyhow::ensure!(
+        output.status.success(),
+        "textconv '{}' failed with {}",
+        command,
+        output.status
+    );
+    Ok(output.stdout)
+}
+
+/// Hash the file at `file` returning the hex SHA, when `write` is set the object is stored too
+/// (uncompressed if `store` is set).
+fn hash_file(file: &Path, write: bool, store: bool) -> anyhow::Result<String> {
+    let (content, len) = open_file(file)?;
+    store::write_blob(content, len, write, store)
+}
+
+/// Open `file` to be read as a blob, returns it with its length.
+fn open_file(file: &Path) -> anyhow::Result<(fs::File, u64)> {
+    let content =
+        fs::File::open(file).with_context(|| format!("no git object at '{}", file.display()))?;
+    let len = content.metadata()?.len();
+    Ok((content, len))
+}
+
+/// Write `<sha> <type> <size>` for every loose object, returns whether all of them could be
+/// read. Objects that can't are reported on stderr, with `allow_unknown_type` an unknown
+/// type is listed as it is.
+fn batch_check_all(allow_unknown_type: bool, out: &mut impl Write) -> anyhow::Result<bool> {
+    let mut ok = true;
+    for object in store::iter_loose_objects(&repo::objects()) {
+        let (sha, bytes) = object?;
+        let header = store::open_loose(bytes.as_slice(), &sha, allow_unknown_type)
+            .map(|(kind, size, _)| (kind, size));
+        match header {
+            Ok((kind, size)) => writeln!(out, "{} {} {}", sha, kind, size)?,
+            Err(e) => {
+                eprintln!("error: {:#}", e);
+                ok = false;
+            }
+        }
+    }
+    Ok(ok)
+}
+
+/// Write the content of an object for `cat-file`, with `print` a tree is listed like
+/// `ls-tree` does, anything else is written as it is stored so a commit or tag hashes
+/// back to its SHA.
+fn write_content(
+    obj_kind: &str,
+    obj_size: usize,
+    mut reader: impl Read,
+    print: bool,
+    color: bool,
+    abbrev: Option<usize>,
+    out: &mut impl Write,
+) -> anyhow::Result<()> {
+    if print && obj_kind == "tree" {
+        let mut content = format!("tree {}\0", obj_size).into_bytes();
+        reader
+            .read_to_end(&mut content)
+            .context("uncompressing object")?;
+        if let ObjType::Tree { objs, .. } = GitObject::try_from_bytes(&content)?.obj_type {
+            for o in objs {
+                writeln!(out, "{}", o.to_full_string(color, abbrev))?;
+            }
+        }
+    } else {
+        io::copy(&mut reader, out).context("uncompressing object")?;
+    }
+    Ok(())
+}
+
+/// The summary line of the commit `sha` the gitlink at `path` (from the top of the work
+/// tree) points at, when `.gitmodules` or `.idiot/config` has a submodule at `path` that is
+/// checked out and has that commit as a loose object.
+fn gitlink_summary(path: &str, sha: &str) -> anyhow::Result<Option<String>> {
+    // A bare repository has nothing checked out
+    let Ok(top) = repo::work_tree() else {
+        return Ok(None);
+    };
+    // Like git any other repository that happens to be at `path` is left alone
+    let configs = [
+        config::Config::read_file(&top.join(".gitmodules")),
+        config::Config::read(),
+    ];
+    let configured = configs.iter().any(|config| {
+        config.subsections("submodule").iter().any(|name| {
+            let sub_path = config.get(&format!("submodule.{}.path", name));
+            sub_path.map(|p| p.trim_end_matches('/')) == Some(path)
+        })
+    });
+    if !configured {
+        return Ok(None);
+    }
+    let Some(sub_repo) = tree::submodule_repo(&top.join(path))? else {
+        return Ok(None);
+    };
+    let Ok(commit) = store::read_object_in(&sub_repo.join("objects"), sha) else {
+        return Ok(None);
+    };
+    match GitObject::try_from_bytes(&commit).map(|o| o.obj_type) {
+        Ok(ObjType::Commit { message, .. }) => Ok(message.lines().next().map(str::to_string)),
+        _ => Ok(None),
+    }
+}
+
+/// Format `bytes` like `xxd`, an offset column, 16 hex bytes and their printable ASCII.
+fn hex_dump(bytes: &[u8]) -> String {
+    let mut res = String::new();
+    for (i, line) in bytes.chunks(16).enumerate() {
+        let hex = line
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<Vec<String>>()
+            .join(" ");
+        let ascii = line
+            .iter()
+            .map(|&b| {
+                if b.is_ascii_graphic() || b == b' ' {
+                    b as char
+                } else {
+                    '.'
+                }
+            })
+            .collect::<String>();
+        writeln!(res, "{:08x}: {:<47}  {}", i * 16, hex, ascii)
+            .expect("valid to write to a string");
+    }
+    res
+}
+
+/// Exit codes follow git's conventions:
+///
+/// - `0` success.
+/// - `1` the command ran but the answer is "no", see each command for when this happens.
+/// - `128` any other error, like git's `fatal:`.
+/// - `129` the command line could not be parsed.
+fn main() -> ExitCode {
+    let args = match Idiot::try_parse() {
+        Ok(args) => args,
+        Err(e) => {
+            // `--help` and `--version` are "errors" printed to stdout
+            let _ = e.print();
+            return if e.use_stderr() {
+                ExitCode::from(129)
+            } else {
+                ExitCode::SUCCESS
+            };
+        }
+    };
+    log::set_level(args.log_level);
+    if let Ok(cwd) = env::current_dir() {
+        repo::open(&repo::discover(&cwd));
+    }
+    let start = Instant::now();
+    let result = run(args);
+    log::info!("finished in {:.1?}", start.elapsed());
+    match result {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("Error: {:?}", e);
+            ExitCode::from(128)
+        }
+    }
+}
+
+fn run(args: Idiot) -> anyhow::Result<ExitCode> {
+    let color = args.color.enabled();
+    let abbrev = args.abbrev.map(usize::from);
+    log::info!("running {:?}", args.command);
     match args.command {
-        Command::Init => {
-            fs::create_dir(IDIOT).unwrap();
-            fs::create_dir(OBJS).unwrap();
-            fs::create_dir(REFS).unwrap();
-            fs::write(HEAD, "ref: refs/heads/master\n").unwrap();
-            println!("Initialized git directory");
-        }
-        Command::CatFile { print } => {
-            let (dir, file) = print.split_at(2);
-            let bytes = fs::read(format!("{}/{}/{}", OBJS, dir, file))
-                .with_context(|| format!("no git object at '{}/{}/{}", OBJS, dir, file))?;
-            let decoded = decomp_obj(&bytes).context("uncompressing object")?;
-            let s = String::from_utf8_lossy(&decoded);
-            print!("{}", s);
-        }
-        Command::HashObject { which } => {
-            let bytes = fs::read(&which).with_context(|| format!("no git object at '{}", which))?;
-            let encoded = compress_obj(&bytes).context("compressing object")?;
-            let mut hasher = Sha1::new();
-            hasher.update(&encoded);
-
-            let sha_hash = hex::encode(hasher.finalize());
-            let (dir, path) = sha_hash.split_at(2);
-
-            match fs::create_dir(format!("{}/{}", OBJS, dir)) {
-                Ok(()) => {}
-                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {}
-                Err(e) => {
-                    anyhow::bail!(e)
+        Command::Init { bare, directory } => {
+            // A bare repository has no work tree, the repository is the directory itself
+            let dir = match bare {
+                true => Path::new(&directory).to_path_buf(),
+                false => Path::new(&directory).join(IDIOT),
+            };
+            init_repo(&dir)?;
+            if !args.quiet {
+                println!("Initialized git directory");
+            }
+        }
+        Command::Archive {
+            format: _,
+            output,
+            prefix,
+            tree,
+        } => {
+            let sha = rev::resolve(&tree)?;
+            // Like git entries are dated by the commit, or now for a bare tree
+            let mtime = match rev::peel(&sha, Some("commit")) {
+                Ok(commit) => commit_time(&commit)?,
+                Err(_) => SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs(),
+            };
+            let tree = rev::peel(&sha, Some("tree"))?;
+            match output {
+                Some(path) => {
+                    let file = fs::File::create(&path)
+                        .with_context(|| format!("failed to write to {}", path))?;
+                    let mut out = BufWriter::new(file);
+                    archive::write_tar(&tree, &prefix, mtime, &mut out)?;
+                    out.flush()?;
+                }
+                None => {
+                    let mut out = BufWriter::new(io::stdout().lock());
+                    archive::write_tar(&tree, &prefix, mtime, &mut out)?;
+                    out.flush()?;
                 }
             }
-            fs::write(format!("{}/{}/{}", OBJS, dir, path), encoded)
-                .with_context(|| format!("failed to write to {}/{}/{}", OBJS, dir, path))?;
-            println!("SHA: {}", sha_hash);
+        }
+        Command::Blame { path } => {
+            let head = rev::peel(&rev::resolve("HEAD")?, Some("commit"))?;
+            let mut out = BufWriter::new(io::stdout().lock());
+            for (sha, line) in blame::blame(&head, &path)? {
+                writeln!(out, "{} {}", rev::abbrev(&sha, abbrev.unwrap_or(7)), line)?;
+            }
+            out.flush()?;
+        }
+        Command::Branch {
+            delete,
+            force_delete,
+            names,
+        } => {
+            let (current, _) = rev::ref_target("HEAD")?;
+            if delete || force_delete {
+                let head = rev::resolve("HEAD").ok();
+                for name in names {
+                    let (full, sha) = rev::ref_target(&format!("refs/heads/{}", name))?;
+                    let sha = sha.with_context(|| format!("branch '{}' not found", name))?;
+                    anyhow::ensure!(
+                        full != current,
+                        "can't delete branch '{}', HEAD is on it",
+                        name
+                    );
+                    let merged = match &head {
+                        Some(head) => is_ancestor(&sha, head)?,
+                        None => false,
+                    };
+                    anyhow::ensure!(
+                        merged || force_delete,
+                        "branch '{}' is not merged into HEAD, use -D to delete it anyway",
+                        name
+                    );
+                    rev::delete_ref(&full, Some(&sha))?;
+                    println!(
+                        "Deleted branch {} (was {}).",
+                        name,
+                        rev::abbrev(&sha, abbrev.unwrap_or(7))
+                    );
+                }
+            } else if let [name, start @ ..] = names.as_slice() {
+                anyhow::ensure!(start.len() <= 1, "expected a branch name and a start point");
+                let start = start.first().map_or("HEAD", |start| start.as_str());
+                let sha = rev::peel(&rev::resolve(start)?, Some("commit"))?;
+                let (full, existing) = rev::ref_target(&format!("refs/heads/{}", name))?;
+                anyhow::ensure!(
+                    existing.is_none(),
+                    "a branch named '{}' already exists",
+                    name
+                );
+                rev::update_ref(&full, &sha, Some(&"0".repeat(40)))?;
+            } else {
+                for (full, _) in rev::list_refs("refs/heads")? {
+                    let mark = if full == current { '*' } else { ' ' };
+                    println!("{} {}", mark, &full["refs/heads/".len()..]);
+                }
+            }
+        }
+        Command::CheckIgnore {
+            verbose,
+            ignore_case,
+            paths,
+        } => {
+            repo::require_work_tree()?;
+            let mut ignore = Ignore::from_root("./");
+            if ignore_case {
+                ignore = ignore.ignore_case(true);
+            }
+            let mut any = false;
+            for path in paths {
+                let mut repo_path = normalize_repo_path(&path)?;
+                // A trailing `/` is how a directory that doesn't exist is asked about
+                if path.ends_with('/') {
+                    repo_path.push('/');
+                }
+                let Some(rule) = ignore.matching_rule(Path::new(&repo_path)) else {
+                    continue;
+                };
+                // A re-included path is only reported along with the `!` rule that did it
+                if rule.is_negated() && !verbose {
+                    continue;
+                }
+                any = true;
+                if verbose && !args.quiet {
+                    let source = rule.source.as_deref().unwrap_or("");
+                    println!("{}:{}:{}\t{}", source, rule.line, rule.pattern, path);
+                } else if !args.quiet {
+                    println!("{}", path);
+                }
+            }
+            return Ok(if any {
+                ExitCode::SUCCESS
+            } else {
+                ExitCode::from(1)
+            });
+        }
+        Command::CatFile {
+            print,
+            kind,
+            size,
+            exists,
+            allow_unknown_type,
+            textconv,
+            batch_check,
+            batch_file,
+            batch_all_objects,
+            report_storage,
+            raw_file,
+            follow_symlinks,
+            object,
+        } => {
+            if batch_check && batch_all_objects {
+                let mut out = BufWriter::new(io::stdout().lock());
+                let ok = batch_check_all(allow_unknown_type, &mut out)?;
+                out.flush()?;
+                return Ok(if ok {
+                    ExitCode::SUCCESS
+                } else {
+                    ExitCode::from(1)
+                });
+            }
+            if batch_check {
+                let input: Box<dyn BufRead> = match batch_file {
+                    Some(path) => Box::new(BufReader::new(
+                        fs::File::open(&path).with_context(|| format!("reading '{}'", path))?,
+                    )),
+                    None => Box::new(io::stdin().lock()),
+                };
+                let mut out = BufWriter::new(io::stdout().lock());
+                for line in input.lines() {
+                    let line = line?;
+                    let name = line.trim();
+                    let resolved = rev::resolve_with(name, follow_symlinks);
+                    match resolved.and_then(|sha| Ok((open_object(&sha)?, sha))) {
+                        Ok(((kind, size, _), sha)) => writeln!(out, "{} {} {}", sha, kind, size)?,
+                        Err(_) => writeln!(out, "{} missing", name)?,
+                    }
+                }
+                out.flush()?;
+                return Ok(ExitCode::SUCCESS);
+            }
+
+            let (obj_kind, obj_size, reader) = if let Some(path) = &raw_file {
+                let opened = fs::File::open(path)
+                    .with_context(|| format!("reading '{}'", path))
+                    .and_then(|file| {
+                        store::open_loose(BufReader::new(file), path, allow_unknown_type)
+                    });
+                if exists {
+                    return Ok(match opened {
+                        Ok(_) => ExitCode::SUCCESS,
+                        Err(_) => ExitCode::from(1),
+                    });
+                }
+                opened?
+            } else {
+                let object = object
+                    .as_deref()
+                    .expect("clap requires an object without --batch-check or --raw-file");
+                if report_storage {
+                    // Objects are only ever read loose, packs are unpacked first
+                    if let Ok(sha) = rev::resolve_with(object, follow_symlinks) {
+                        match fs::metadata(store::object_path(&sha)?) {
+                            Ok(meta) => {
+                                eprintln!("{}: loose, {} bytes on disk", sha, meta.len())
+                            }
+                            Err(_) if sha == tree::EMPTY_TREE_SHA => {
+                                eprintln!("{}: not stored, the empty tree is built in", sha)
+                            }
+                            Err(_) => eprintln!("{}: not stored", sha),
+                        }
+                    }
+                }
+                if exists {
+                    let resolved = rev::resolve_with(object, follow_symlinks);
+                    return Ok(match resolved.and_then(|sha| open_object(&sha)) {
+                        Ok(_) => ExitCode::SUCCESS,
+                        Err(_) => ExitCode::from(1),
+                    });
+                }
+                open_object_as(
+                    &rev::resolve_with(object, follow_symlinks)?,
+                    allow_unknown_type,
+                )?
+            };
+            if kind {
+                println!("{}", obj_kind);
+            } else if size {
+                println!("{}", obj_size);
+            } else if let Some(command) = textconv
+                .then(|| object.as_deref().and_then(textconv_command))
+                .flatten()
+                .filter(|_| obj_kind == "blob")
+            {
+                let text = run_textconv(&command, reader)?;
+                io::stdout().lock().write_all(&text)?;
+            } else {
+                // Stdout is line buffered, which means a write per line of a large blob
+                let mut out = BufWriter::new(io::stdout().lock());
+                write_content(&obj_kind, obj_size, reader, print, color, abbrev, &mut out)?;
+                out.flush()?;
+            }
+        }
+        Command::Clean {
+            force,
+            dry_run,
+            ignored,
+        } => {
+            let top = repo::work_tree()?;
+            let ignore = if ignored {
+                Ignore::repo_dirs()
+            } else {
+                Ignore::from_root(&top)
+            };
+            let tracked = index::read_index()?
+                .into_iter()
+                .map(|e| e.path)
+                .collect::<HashSet<_>>();
+            let mut files = vec![];
+            untracked_files(&top, "", &ignore, &tracked, &mut files)?;
+            files.sort();
+            for path in files {
+                if force && !dry_run {
+                    remove_work_tree_file(&top, &path)?;
+                    if !args.quiet {
+                        println!("Removing {}", path);
+                    }
+                } else if !args.quiet {
+                    println!("Would remove {}", path);
+                }
+            }
+        }
+        Command::Clone { bare, source, dest } => {
+            let mut origin = fetch::Local::open(Path::new(&source))?;
+            let top = env::current_dir()?.join(&dest);
+            anyhow::ensure!(
+                top.read_dir()
+                    .map_or(!top.exists(), |mut entries| entries.next().is_none()),
+                "destination '{}' already exists and is not an empty directory",
+                dest
+            );
+            let refs = origin.refs()?;
+            let head_branch = origin.head_branch()?;
+
+            fs::create_dir_all(&top)?;
+            let dir = if bare { top.clone() } else { top.join(IDIOT) };
+            init_repo(&dir)?;
+            // Everything from here on works in the new repository
+            repo::open(&dir);
+            let tips = refs.iter().map(|(_, sha)| sha.clone()).collect::<Vec<_>>();
+            fetch::copy_reachable(
+                &tips,
+                |sha| origin.object(sha, args.store),
+                args.store,
+                args.quiet,
+            )?;
+
+            // Like git a bare clone keeps the branches as they are, it has nothing to track
+            let url = origin.path().to_string_lossy();
+            let fetch = "+refs/heads/*:refs/remotes/origin/*";
+            let remote = if bare {
+                vec![("url", url.as_ref())]
+            } else {
+                vec![("url", url.as_ref()), ("fetch", fetch)]
+            };
+            config::Config::append_section("remote", "origin", &remote)?;
+            let mut head = None;
+            for (name, sha) in &refs {
+                let local = match name.strip_prefix("refs/heads/") {
+                    Some(_) if bare => name.clone(),
+                    Some(branch) => format!("refs/remotes/origin/{}", branch),
+                    None if name.starts_with("refs/tags/") => name.clone(),
+                    None if name == "HEAD" => {
+                        head = Some(sha.clone());
+                        continue;
+                    }
+                    None => continue,
+                };
+                rev::update_ref(&local, sha, None)?;
+            }
+            // HEAD is on the same branch as the source's, or detached at the same commit
+            if let Some(branch) = &head_branch {
+                rev::ref_target(branch)?;
+                fs::write(repo::dir().join("HEAD"), format!("ref: {}\n", branch))?;
+                if let Some(sha) = &head {
+                    rev::update_ref(branch, sha, None)?;
+                }
+            } else if let Some(sha) = &head {
+                fs::write(repo::dir().join("HEAD"), format!("{}\n", sha))?;
+            }
+            match head {
+                Some(sha) if !bare => reset_index(&rev::peel(&sha, Some("commit"))?, Some(&top))?,
+                Some(_) => {}
+                None => eprintln!("warning: cloned an empty repository"),
+            }
+            if !args.quiet {
+                println!("Cloned '{}' into '{}'", source, dest);
+            }
+        }
+        Command::Describe { always, rev } => {
+            let sha = rev::peel(&rev::resolve(&rev)?, Some("commit"))?;
+            let short = rev::abbrev(&sha, abbrev.unwrap_or(7));
+            match nearest_tag(&sha)? {
+                Some((tag, 0)) => println!("{}", tag),
+                Some((tag, dist)) => println!("{}-{}-g{}", tag, dist, short),
+                None if always => println!("{}", short),
+                None => anyhow::bail!("no tags can describe '{}'", sha),
+            }
+        }
+        Command::Fetch { remote } => {
+            let url = config::Config::read()
+                .get(&format!("remote.{}.url", remote))
+                .map(str::to_string)
+                .with_context(|| format!("no remote named '{}'", remote))?;
+            anyhow::ensure!(
+                url.starts_with("http://") || url.starts_with("https://"),
+                "can't fetch from {}, only http(s) remotes are supported",
+                url
+            );
+            let mut http = fetch::DumbHttp::new(&url);
+            let branches = http
+                .refs()?
+                .into_iter()
+                .filter_map(|(name, sha)| {
+                    Some((name.strip_prefix("refs/heads/")?.to_string(), sha))
+                })
+                .collect::<Vec<_>>();
+            let tips = branches
+                .iter()
+                .map(|(_, sha)| sha.clone())
+                .collect::<Vec<_>>();
+            fetch::copy_reachable(
+                &tips,
+                |sha| http.object(sha, args.store),
+                args.store,
+                args.quiet,
+            )?;
+
+            let mut updated = vec![];
+            for (branch, sha) in branches {
+                let tracking = format!("refs/remotes/{}/{}", remote, branch);
+                let old = rev::read_ref(&tracking).ok();
+                if old.as_deref() != Some(sha.as_str()) {
+                    rev::update_ref(&tracking, &sha, None)?;
+                    updated.push((branch, old, sha));
+                }
+            }
+            if !args.quiet && !updated.is_empty() {
+                println!("From {}", url);
+                for (branch, old, sha) in updated {
+                    let summary = match old {
+                        Some(old) => format!(
+                            "{}..{}",
+                            rev::abbrev(&old, abbrev.unwrap_or(7)),
+                            rev::abbrev(&sha, abbrev.unwrap_or(7))
+                        ),
+                        None => "[new branch]".to_string(),
+                    };
+                    println!(" {:<17} {} -> {}/{}", summary, branch, remote, branch);
+                }
+            }
+        }
+        Command::ForEachRef { format, patterns } => {
+            let parts = parse_ref_format(&format)?;
+            let mut out = BufWriter::new(io::stdout().lock());
+            for (name, sha) in rev::list_refs("refs")? {
+                if !patterns.is_empty() && !patterns.iter().any(|p| name.starts_with(p.as_str())) {
+                    continue;
+                }
+                for part in &parts {
+                    match part {
+                        FormatPart::Text(text) => write!(out, "{}", text)?,
+                        FormatPart::Field("refname") => write!(out, "{}", name)?,
+                        FormatPart::Field("refname:short") => {
+                            let short = ["refs/heads/", "refs/tags/", "refs/remotes/", "refs/"]
+                                .iter()
+                                .find_map(|prefix| name.strip_prefix(prefix))
+                                .unwrap_or(&name);
+                            write!(out, "{}", short)?
+                        }
+                        FormatPart::Field("objectname") => write!(out, "{}", sha)?,
+                        FormatPart::Field("objectname:short") => {
+                            write!(out, "{}", rev::abbrev(&sha, abbrev.unwrap_or(7)))?
+                        }
+                        FormatPart::Field("objecttype") => write!(out, "{}", open_object(&sha)?.0)?,
+                        FormatPart::Field(field) => unreachable!("unchecked field '{}'", field),
+                    }
+                }
+                writeln!(out)?;
+            }
+            out.flush()?;
+        }
+        Command::HashObject {
+            write,
+            mut files,
+            stdin_paths,
+            null,
+            verbose,
+            stdout,
+        } => {
+            if stdin_paths {
+                let mut input = vec![];
+                io::stdin().lock().read_to_end(&mut input)?;
+                let sep = if null { b'\0' } else { b'\n' };
+                files.extend(
+                    input
+                        .split(|ch| *ch == sep)
+                        .filter(|path| !path.is_empty())
+                        .map(|path| String::from_utf8_lossy(path).to_string()),
+                );
+            }
+            for file in files {
+                if stdout {
+                    let mut out = io::stdout().lock();
+                    if write {
+                        let path =
+                            store::object_path(&hash_file(Path::new(&file), true, args.store)?)?;
+                        io::copy(&mut fs::File::open(path)?, &mut out)?;
+                    } else {
+                        let (content, len) = open_file(Path::new(&file))?;
+                        compress_from(store::blob_reader(content, len), &mut out, args.store)?;
+                    }
+                    continue;
+                }
+                let sha_hash = hash_file(Path::new(&file), write, args.store)?;
+                if verbose && !args.quiet {
+                    println!("SHA: {}", sha_hash);
+                } else {
+                    println!("{}", sha_hash);
+                }
+            }
+        }
+        Command::LsFiles { stage, null } => {
+            repo::require_work_tree()?;
+            let mut out = BufWriter::new(io::stdout().lock());
+            for entry in index::read_index()? {
+                if stage {
+                    let sha = hex::encode(entry.sha);
+                    let sha = abbrev.map_or(sha.clone(), |len| rev::abbrev(&sha, len));
+                    write!(out, "{} {} {}\t", entry.mode as usize, sha, entry.stage)?;
+                }
+                let end = if null { '\0' } else { '\n' };
+                write!(
+                    out,
+                    "{}{}",
+                    color::paint(entry.mode, &entry.path, color),
+                    end
+                )?;
+            }
+            out.flush()?;
         }
         Command::LsTree {
             name_only,
+            recursive,
+            relative,
+            null,
+            directories_marked,
+            trees_only,
+            blobs_only,
+            dereference_gitlinks,
             tree_sha,
         } => {
-            let (dir, file) = tree_sha.split_at(2);
-            let bytes = fs::read(format!("{}/{}/{}", OBJS, dir, file))
-                .with_context(|| format!("no git object at '{}/{}/{}", OBJS, dir, file))?;
-            let encoded = decomp_obj(&bytes).context("decompressing object")?;
-            let tree = GitObject::from_bytes(&encoded);
+            let end = if null { "\0" } else { "\n" };
+            // Like git a commit (or a tag of one) lists the commit's tree
+            let sha = rev::peel(&rev::resolve(&tree_sha)?, Some("tree"))?;
+            let tree = GitObject::try_from_bytes(&read_object(&sha)?)?;
 
-            if let ObjType::Tree { size, objs, .. } = tree.obj_type {
+            if let ObjType::Tree { size, mut objs, .. } = tree.obj_type {
+                if recursive {
+                    let mut all = vec![];
+                    flatten_tree(objs, "", trees_only, &mut all)?;
+                    objs = all;
+                }
+                if trees_only {
+                    objs.retain(|o| matches!(o.mode, Mode::SubDir));
+                } else if blobs_only {
+                    objs.retain(|o| !matches!(o.mode, Mode::SubDir | Mode::SubMod));
+                }
+                // Paths are printed relative to this directory
+                let mut base = String::new();
+                if let Some(dir) = relative {
+                    let dir = format!("{}/", dir.trim_matches('/'));
+                    base = dir.clone();
+                    objs.retain(|o| o.as_path_str().starts_with(&dir));
+                    for o in &mut objs {
+                        if let ObjType::Blob { path, .. } = &mut o.obj_type {
+                            path.drain(..dir.len());
+                        }
+                    }
+                }
                 if name_only {
-                    let mut sorted = objs.iter().map(|o| o.as_path_str()).collect::<Vec<&str>>();
-                    sorted.sort();
-                    println!("{}", sorted.join("\n"));
-                } else {
-                    println!(
-                        "tree {} (SHA: {})",
-                        size,
-                        tree.sha.map(hex::encode).unwrap_or("NONE".to_string())
-                    );
-                    let obj_list = objs
+                    let mut sorted = objs.iter().collect::<Vec<&GitObject>>();
+                    sorted.sort_by_key(|o| o.as_path_str());
+                    let names = sorted
                         .iter()
-                        .map(|o| o.to_full_string())
+                        .map(|o| {
+                            let mark = directories_marked && matches!(o.mode, Mode::SubDir);
+                            let name =
+                                format!("{}{}", o.as_path_str(), if mark { "/" } else { "" });
+                            color::paint(o.mode, &name, color)
+                        })
                         .collect::<Vec<String>>();
-                    println!("{}", obj_list.join("\n"));
+                    print!("{}{}", names.join(end), end);
+                } else {
+                    if !args.quiet && !null {
+                        println!("tree {} (SHA: {})", size, sha);
+                    }
+                    let mut obj_list = vec![];
+                    for o in &objs {
+                        let mut line = o.to_full_string(color, abbrev);
+                        if let (true, Mode::SubMod, Some(sha)) =
+                            (dereference_gitlinks, o.mode, &o.sha)
+                        {
+                            let path = format!("{}{}", base, o.as_path_str());
+                            if let Some(summary) = gitlink_summary(&path, &hex::encode(sha))? {
+                                line.push_str(&format!(" ({})", summary));
+                            }
+                        }
+                        obj_list.push(line);
+                    }
+                    print!("{}{}", obj_list.join(end), end);
+                }
+            }
+        }
+        Command::MkTree => println!("{}", mk_tree(io::stdin().lock(), args.store)?),
+        Command::PackObjects { stdout } => {
+            let mut shas = vec![];
+            for line in io::stdin().lock().lines() {
+                let line = line?;
+                if !line.trim().is_empty() {
+                    shas.push(rev::resolve(line.trim())?);
                 }
             }
+            let (pack, idx) = pack::write_pack(&shas)?;
+            if stdout {
+                io::stdout().lock().write_all(&pack)?;
+            } else {
+                let name = hex::encode(&pack[pack.len() - 20..]);
+                let dir = repo::objects().join("pack");
+                fs::create_dir_all(&dir)?;
+                // The index goes last, git only looks at packs that have one
+                fs::write(dir.join(format!("pack-{}.pack", name)), &pack)?;
+                fs::write(dir.join(format!("pack-{}.idx", name)), &idx)?;
+                println!("{}", name);
+            }
         }
-        Command::WriteTree => {
-            let tree = GitObject::from_path("./")?;
-            if let ObjType::Tree { size, objs, path: tree_path } = tree.obj_type {
-                let hash_str = tree.sha.as_ref().map(hex::encode).unwrap();
-                let (dir, path) = hash_str.split_at(2);
-                match fs::create_dir(format!("{}/{}", OBJS, dir)) {
-                    Ok(()) => {}
-                    Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {}
-                    Err(e) => {
-                        anyhow::bail!(e)
+        Command::PackRefs => {
+            let packed = rev::pack_refs()?;
+            if !args.quiet {
+                println!("Packed {} refs", packed);
+            }
+        }
+        Command::Remote { verbose, action } => match action {
+            Some(RemoteAction::Add { name, url }) => {
+                // The name ends up in ref names and in a quoted config section header
+                anyhow::ensure!(
+                    rev::ref_target(&format!("refs/remotes/{}", name)).is_ok()
+                        && !name
+                            .contains(|ch: char| ch.is_whitespace() || "\"\\:*?[~^".contains(ch)),
+                    "'{}' is not a valid remote name",
+                    name
+                );
+                check_remote_url(&url)?;
+                let config = config::Config::read();
+                anyhow::ensure!(
+                    !config.subsections("remote").contains(&name.as_str()),
+                    "remote '{}' already exists",
+                    name
+                );
+                let fetch = format!("+refs/heads/*:refs/remotes/{}/*", name);
+                config::Config::append_section(
+                    "remote",
+                    &name,
+                    &[("url", &url), ("fetch", &fetch)],
+                )?;
+            }
+            None => {
+                let config = config::Config::read();
+                for name in config.subsections("remote") {
+                    let url = config.get(&format!("remote.{}.url", name));
+                    match url.filter(|_| verbose) {
+                        Some(url) => {
+                            println!("{}\t{} (fetch)", name, url);
+                            println!("{}\t{} (push)", name, url);
+                        }
+                        None => println!("{}", name),
                     }
                 }
-                let mut bytes = format!("tree {}\0", size).into_bytes();
-                bytes.extend(objs.iter().flat_map(|o| o.tree_content_bytes()));
-                let content = compress_obj(&bytes).context("compressing object")?;
-
-                fs::write(format!("{}/{}/{}", OBJS, dir, path), content)
-                    .with_context(|| format!("failed to write to {}/{}/{}", OBJS, dir, path))?;
-
+            }
+        },
+        Command::Reset {
+            soft,
+            mixed: _,
+            hard,
+            dry_run,
+            target,
+        } => {
+            let commit = rev::peel(&rev::resolve(&target)?, Some("commit"))
+                .with_context(|| format!("can't reset to '{}'", target))?;
+            if dry_run {
+                let (name, old) = rev::ref_target("HEAD")?;
                 println!(
-                    "tree {} (SHA: {} {:?})",
-                    size,
-                    tree.sha.map(hex::encode).unwrap_or("NONE".to_string()),
-                    tree_path
+                    "{}: {} -> {}",
+                    name,
+                    old.as_deref().unwrap_or("(none)"),
+                    commit
                 );
+                if !soft {
+                    let tree = rev::peel(&commit, Some("tree"))?;
+                    let work_tree = if hard { " and work tree" } else { "" };
+                    println!("index{}: -> tree {}", work_tree, tree);
+                }
+                return Ok(ExitCode::SUCCESS);
+            }
+            if !soft {
+                let top = repo::work_tree()?;
+                reset_index(&commit, hard.then_some(top.as_path()))?;
+            }
+            rev::set_head(&commit)?;
+        }
+        Command::RevList {
+            max_count,
+            first_parent,
+            rev,
+        } => {
+            let start = rev::peel(&rev::resolve(&rev)?, Some("commit"))?;
+            let mut out = BufWriter::new(io::stdout().lock());
+            let walk = CommitWalk::new(&start)
+                .first_parent(first_parent)
+                .max_count(max_count);
+            for commit in walk {
+                let sha = commit?.sha;
+                writeln!(
+                    out,
+                    "{}",
+                    abbrev.map_or(sha.clone(), |len| rev::abbrev(&sha, len))
+                )?;
+            }
+            out.flush()?;
+        }
+        Command::RevParse {
+            ignore_missing,
+            abbrev_ref,
+            revs,
+        } => {
+            for name in revs {
+                if abbrev_ref {
+                    if let Some(short) = rev::abbrev_ref(&name)? {
+                        println!("{}", short);
+                        continue;
+                    }
+                }
+                match rev::resolve(&name) {
+                    Ok(sha) => println!("{}", sha),
+                    Err(_) if ignore_missing => {}
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+        Command::ShowRef { heads, tags } => {
+            let mut refs = vec![];
+            if !heads && !tags {
+                // An unborn HEAD has nothing to show
+                if let Ok(sha) = rev::read_ref("HEAD") {
+                    refs.push(("HEAD".to_string(), sha));
+                }
+                refs.extend(rev::list_refs("refs")?);
+            } else {
+                if heads {
+                    refs.extend(rev::list_refs("refs/heads")?);
+                }
+                if tags {
+                    refs.extend(rev::list_refs("refs/tags")?);
+                }
+            }
+            if refs.is_empty() {
+                return Ok(ExitCode::from(1));
+            }
+            let mut out = BufWriter::new(io::stdout().lock());
+            for (name, sha) in refs {
+                let sha = abbrev.map_or(sha.clone(), |len| rev::abbrev(&sha, len));
+                writeln!(out, "{} {}", sha, name)?;
+            }
+            out.flush()?;
+        }
+        Command::UnpackObjects { pack } => {
+            let bytes = match pack {
+                Some(path) => fs::read(&path).with_context(|| format!("reading '{}'", path))?,
+                None => {
+                    let mut bytes = vec![];
+                    io::stdin().lock().read_to_end(&mut bytes)?;
+                    bytes
+                }
+            };
+            for (kind, content) in pack::read_pack(&bytes)? {
+                let mut obj = format!("{} {}\0", kind, content.len()).into_bytes();
+                obj.extend(content);
+                write_object(&obj, args.store)?;
+            }
+        }
+        Command::UpdateRef {
+            dry_run,
+            stdin,
+            delete,
+            name,
+            new,
+            old,
+        } => {
+            let mut updates = vec![];
+            if stdin {
+                for (idx, line) in io::stdin().lock().lines().enumerate() {
+                    let line = line?;
+                    if !line.trim().is_empty() {
+                        updates
+                            .push(ref_update(&line).with_context(|| format!("line {}", idx + 1))?);
+                    }
+                }
+            } else if let (Some(name), true) = (&name, delete) {
+                updates.push(RefUpdate {
+                    name: name.clone(),
+                    new: None,
+                    old: old_value(new)?,
+                });
+            } else if let (Some(name), Some(new)) = (name, new) {
+                updates.push(RefUpdate {
+                    name,
+                    new: Some(rev::resolve(&new)?),
+                    old: old_value(old)?,
+                });
+            }
+
+            // Everything is checked up front so a bad update leaves every ref alone
+            let mut targets = vec![];
+            for update in &updates {
+                let (full, current) = rev::ref_target(&update.name)?;
+                anyhow::ensure!(
+                    !targets.iter().any(|(other, _)| *other == full),
+                    "ref '{}' is updated twice",
+                    full
+                );
+                if let Some(old) = &update.old {
+                    rev::check_old_value(&full, old)?;
+                }
+                anyhow::ensure!(
+                    update.new.is_some() || current.is_some(),
+                    "ref '{}' doesn't exist",
+                    full
+                );
+                targets.push((full, current));
+            }
+            for (update, (full, current)) in updates.iter().zip(targets) {
+                if dry_run {
+                    let current = current.as_deref().unwrap_or("(none)");
+                    let new = update.new.as_deref().unwrap_or("(deleted)");
+                    println!("{}: {} -> {}", full, current, new);
+                } else if let Some(sha) = &update.new {
+                    rev::update_ref(&update.name, sha, update.old.as_deref())?;
+                } else {
+                    rev::delete_ref(&update.name, update.old.as_deref())?;
+                }
+            }
+        }
+        Command::VerifyRepo => {
+            let commit_sha = rev::resolve("HEAD")?;
+            let verified = verify_object(&commit_sha)
+                .with_context(|| format!("broken commit {} at HEAD", commit_sha))
+                .and_then(|commit| {
+                    let commit = GitObject::try_from_bytes(&commit)
+                        .with_context(|| format!("broken commit {} at HEAD", commit_sha))?;
+                    match commit.obj_type {
+                        ObjType::Commit { tree, .. } => verify_tree(&tree, ""),
+                        _ => anyhow::bail!("HEAD {} is not a commit", commit_sha),
+                    }
+                });
+            match verified {
+                Ok(count) if !args.quiet => {
+                    println!("HEAD {} ok, {} objects verified", commit_sha, count + 1)
+                }
+                Ok(_) => {}
+                Err(e) => {
+                    eprintln!("Error: {:?}", e);
+                    return Ok(ExitCode::from(1));
+                }
+            }
+        }
+        Command::WriteTree {
+            index,
+            missing_ok,
+            dump_bytes,
+            verbose,
+            progress,
+            no_gitignore,
+            recurse_submodules,
+        } => {
+            let tree = if index {
+                let sha = write_index_tree(&index::read_index()?, "", missing_ok, args.store)?;
+                let mut tree = GitObject::try_from_bytes(&read_object(&sha)?)?;
+                tree.sha = Some(hex::decode(sha)?);
+                tree
+            } else {
+                repo::require_work_tree()?;
+                let ignore = if no_gitignore {
+                    Ignore::repo_dirs()
+                } else {
+                    Ignore::from_root("./")
+                };
+                let mut progress = Progress::new("Hashing files", progress, args.quiet);
+                let tree = GitObject::write_path(
+                    "./",
+                    &ignore,
+                    &mut progress,
+                    args.store,
+                    recurse_submodules,
+                )?;
+                progress.finish();
+                tree
+            };
+            let hash_str = hex::encode(tree.sha.as_ref().expect("a written tree has a sha"));
+            if let ObjType::Tree { size, objs, .. } = tree.obj_type {
+                if dump_bytes {
+                    let mut bytes = format!("tree {}\0", size).into_bytes();
+                    for o in &objs {
+                        bytes.extend(o.tree_content_bytes()?);
+                    }
+                    eprint!("{}", hex_dump(&bytes));
+                }
+
+                if args.quiet || !verbose {
+                    println!("{}", hash_str);
+                    return Ok(ExitCode::SUCCESS);
+                }
+                // The same header `ls-tree` prints, the root tree has no name to show
+                println!("tree {} (SHA: {})", size, hash_str);
                 let obj_list = objs
                     .iter()
-                    .map(|o| o.to_full_string())
+                    .map(|o| o.to_full_string(color, abbrev))
                     .collect::<Vec<String>>();
                 println!("{}", obj_list.join("\n"));
             }
         }
     }
-    Ok(())
+    Ok(ExitCode::SUCCESS)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::CommandFactory;
+
+    use crate::testutil::{blob, commit, temp_dir, temp_repo, tree};
+
+    fn idiot(args: &[&str]) -> anyhow::Result<ExitCode> {
+        run(Idiot::try_parse_from([&["idiot"], args].concat())?)
+    }
+
+    /// The first line of `name`'s doc comment, as `idiot --help` lists it.
+    fn about(name: &str) -> String {
+        Idiot::command()
+            .find_subcommand(name)
+            .and_then(|cmd| cmd.get_about().map(ToString::to_string))
+            .unwrap_or_default()
+    }
+
+    fn index_paths() -> Vec<String> {
+        index::read_index()
+            .unwrap()
+            .into_iter()
+            .map(|e| e.path)
+            .collect()
+    }
+
+    #[test]
+    fn reset_soft_moves_only_the_branch() {
+        let top = temp_repo("reset-soft");
+        let first = commit(&tree(&[(Mode::FileBlob, "a", &blob("one"))]), &[], "first");
+        let second = commit(
+            &tree(&[(Mode::FileBlob, "b", &blob("two"))]),
+            &[&first],
+            "second",
+        );
+        idiot(&["reset", "--hard", &first]).unwrap();
+
+        idiot(&["reset", "--soft", &second]).unwrap();
+        assert_eq!(rev::read_ref("refs/heads/master").unwrap(), second);
+        assert_eq!(index_paths(), ["a"]);
+        assert!(top.join("a").is_file() && !top.join("b").exists());
+    }
+
+    #[test]
+    fn reset_mixed_resets_the_index_but_not_the_work_tree() {
+        let top = temp_repo("reset-mixed");
+        let first = commit(&tree(&[(Mode::FileBlob, "a", &blob("one"))]), &[], "first");
+        let second = commit(
+            &tree(&[(Mode::FileBlob, "b", &blob("two"))]),
+            &[&first],
+            "second",
+        );
+        idiot(&["reset", "--hard", &first]).unwrap();
+
+        idiot(&["reset", &second]).unwrap();
+        assert_eq!(rev::read_ref("refs/heads/master").unwrap(), second);
+        assert_eq!(index_paths(), ["b"]);
+        assert!(top.join("a").is_file() && !top.join("b").exists());
+    }
+
+    #[test]
+    fn reset_hard_turns_a_file_into_a_directory_and_back() {
+        let top = temp_repo("reset-file-dir");
+        let file = commit(
+            &tree(&[(Mode::FileBlob, "foo", &blob("file"))]),
+            &[],
+            "file",
+        );
+        let sub = tree(&[(Mode::FileBlob, "bar", &blob("nested"))]);
+        let dir = commit(&tree(&[(Mode::SubDir, "foo", &sub)]), &[&file], "dir");
+        idiot(&["reset", "--hard", &file]).unwrap();
+
+        idiot(&["reset", "--hard", &dir]).unwrap();
+        assert_eq!(fs::read_to_string(top.join("foo/bar")).unwrap(), "nested");
+        assert_eq!(index_paths(), ["foo/bar"]);
+
+        idiot(&["reset", "--hard", &file]).unwrap();
+        assert_eq!(fs::read_to_string(top.join("foo")).unwrap(), "file");
+        assert_eq!(index_paths(), ["foo"]);
+    }
+
+    #[test]
+    fn clone_reads_a_packed_repository() {
+        let source = temp_repo("clone-packed-source");
+        let a = blob("one");
+        let tree = tree(&[(Mode::FileBlob, "a", &a)]);
+        let first = commit(&tree, &[], "first");
+        rev::set_head(&first).unwrap();
+        let (pack, _) = pack::write_pack(&[a, tree, first.clone()]).unwrap();
+        fs::remove_dir_all(repo::objects()).unwrap();
+        fs::create_dir_all(repo::objects().join("pack")).unwrap();
+        fs::write(repo::objects().join("pack/pack-test.pack"), pack).unwrap();
+
+        let dest = source.with_file_name(format!("{}-clone", source.display()));
+        idiot(&["clone", &source.to_string_lossy(), &dest.to_string_lossy()]).unwrap();
+        assert_eq!(repo::dir(), dest.join(IDIOT));
+        assert_eq!(rev::read_ref("refs/heads/master").unwrap(), first);
+        assert_eq!(fs::read_to_string(dest.join("a")).unwrap(), "one");
+
+        repo::open(&source.join(IDIOT));
+        let bare = source.with_file_name(format!("{}-bare", source.display()));
+        idiot(&[
+            "clone",
+            "--bare",
+            &source.to_string_lossy(),
+            &bare.to_string_lossy(),
+        ])
+        .unwrap();
+        assert_eq!(repo::dir(), bare);
+        assert!(repo::is_bare());
+        assert_eq!(rev::read_ref("refs/heads/master").unwrap(), first);
+        assert!(!bare.join("a").exists() && !bare.join(IDIOT).exists());
+        assert!(!bare.join("index").exists());
+    }
+
+    #[test]
+    fn init_bare_has_no_work_tree() {
+        let top = temp_dir("init-bare");
+        idiot(&["init", "--bare", &top.to_string_lossy()]).unwrap();
+        assert!(top.join("HEAD").is_file());
+        assert!(top.join("objects").is_dir() && top.join("refs").is_dir());
+        assert!(!top.join(IDIOT).exists());
+
+        repo::open(&repo::discover(&top));
+        assert!(repo::is_bare());
+        for args in [&["write-tree"][..], &["ls-files"], &["check-ignore", "a"]] {
+            let err = idiot(args).unwrap_err();
+            assert!(format!("{:#}", err).contains("must be run in a work tree"));
+        }
+        let first = commit(tree::EMPTY_TREE_SHA, &[], "first");
+        let err = idiot(&["reset", "--hard", &first]).unwrap_err();
+        assert!(format!("{:#}", err).contains("must be run in a work tree"));
+    }
+
+    #[test]
+    fn gitlink_summary_follows_only_configured_submodules() {
+        let top = temp_repo("gitlink-summary");
+        for sub in ["lib", "other"] {
+            init_repo(&top.join(sub).join(IDIOT)).unwrap();
+        }
+        repo::open(&top.join("lib").join(IDIOT));
+        let sha = commit(tree::EMPTY_TREE_SHA, &[], "the lib\n\nmore");
+        repo::open(&top.join(IDIOT));
+        assert_eq!(gitlink_summary("lib", &sha).unwrap(), None);
+
+        fs::write(
+            top.join(".gitmodules"),
+            "[submodule \"lib\"]\n\tpath = lib\n\turl = ../lib\n",
+        )
+        .unwrap();
+        assert_eq!(
+            gitlink_summary("lib", &sha).unwrap().as_deref(),
+            Some("the lib")
+        );
+        assert_eq!(gitlink_summary("other", &sha).unwrap(), None);
+        assert_eq!(gitlink_summary("missing", &sha).unwrap(), None);
+    }
+
+    #[test]
+    fn clone_refuses_paths_out_of_the_work_tree() {
+        let source = temp_repo("clone-traversal");
+        let evil = tree(&[(Mode::FileBlob, "evil", &blob("gotcha"))]);
+        rev::set_head(&commit(&tree(&[(Mode::SubDir, "..", &evil)]), &[], "up")).unwrap();
+
+        let dest = source.join("clone");
+        let err = idiot(&["clone", &source.to_string_lossy(), &dest.to_string_lossy()]);
+        assert!(format!("{:#}", err.unwrap_err()).contains("invalid tree entry name '..'"));
+        assert!(!source.join("evil").exists());
+    }
+
+    #[test]
+    fn reset_hard_refuses_paths_out_of_the_work_tree() {
+        let top = temp_repo("reset-traversal");
+        let evil = tree(&[(Mode::FileBlob, "evil", &blob("gotcha"))]);
+        let up = commit(&tree(&[(Mode::SubDir, "..", &evil)]), &[], "up");
+        let err = idiot(&["reset", "--hard", &up]).unwrap_err();
+        assert!(format!("{:#}", err).contains("invalid tree entry name '..'"));
+        assert!(!top.parent().unwrap().join("evil").exists());
+        assert!(rev::read_ref("refs/heads/master").is_err());
+    }
+
+    #[test]
+    fn verify_repo_reports_a_junk_tree() {
+        temp_repo("verify-junk-tree");
+        let junk = store::write_object(b"tree 9\0999 junk\0", false).unwrap();
+        let top = tree(&[(Mode::SubDir, "sub", &junk)]);
+        rev::set_head(&commit(&top, &[], "junk")).unwrap();
+
+        let err = verify_tree(&top, "").unwrap_err();
+        let msg = format!("{:#}", err);
+        assert!(
+            msg.contains(&format!("broken tree {} at 'sub/'", junk)),
+            "{}",
+            msg
+        );
+        assert!(msg.contains("bad mode '999'"), "{}", msg);
+        assert_eq!(idiot(&["verify-repo"]).unwrap(), ExitCode::from(1));
+    }
+
+    #[test]
+    fn cat_file_prints_a_commit_as_stored() {
+        temp_repo("cat-file-commit");
+        // `gpgsig` before `author` would be moved last by re-rendering the commit
+        let text = format!(
+            "tree {}\ngpgsig -----BEGIN PGP SIGNATURE-----\n sig\n -----END PGP SIGNATURE-----\n\
+             author A U Thor <author@example.com> 1112911993 -0700\n\
+             committer C O Mitter <committer@example.com> 1112911993 -0700\n\nsigned\n",
+            tree::EMPTY_TREE_SHA
+        );
+        let mut obj = format!("commit {}\0", text.len()).into_bytes();
+        obj.extend(text.as_bytes());
+        let sha = store::write_object(&obj, false).unwrap();
+
+        let (kind, size, reader) = open_object(&sha).unwrap();
+        let mut out = format!("{} {}\0", kind, size).into_bytes();
+        write_content(&kind, size, reader, true, false, None, &mut out).unwrap();
+        assert_eq!(hex::encode(Sha1::digest(&out)), sha);
+    }
+
+    #[test]
+    fn rev_list_reports_a_commit_without_a_tree() {
+        temp_repo("rev-list-no-tree");
+        let text = "author A U Thor <author@example.com> 1112911993 -0700\n\nno tree\n";
+        let mut obj = format!("commit {}\0", text.len()).into_bytes();
+        obj.extend(text.as_bytes());
+        let sha = store::write_object(&obj, false).unwrap();
+
+        let err = idiot(&["rev-list", &sha]).unwrap_err();
+        assert!(format!("{:#}", err).contains("no tree"), "{:#}", err);
+        idiot(&["cat-file", "-p", &sha]).unwrap();
+    }
+
+    #[test]
+    fn help_lists_each_subcommand_with_its_own_doc() {
+        assert!(about("fetch").starts_with("Download the branches"));
+        assert!(about("for-each-ref").starts_with("Print a line per ref"));
+        assert!(about("hash-object").starts_with("Compute the blob SHA"));
+        assert!(about("remote").starts_with("List the remotes"));
+        assert!(about("reset").starts_with("Move the current branch"));
+    }
+
+    #[test]
+    fn mk_tree_reads_what_ls_tree_prints() {
+        temp_repo("mk-tree-pipe");
+        let sub = tree(&[(Mode::FileBlob, "b", &blob("two"))]);
+        let sha = tree(&[
+            (Mode::FileBlob, "a", &blob("one")),
+            (Mode::ExeBlob, "run", &blob("#!/bin/sh")),
+            (Mode::SubDir, "sub", &sub),
+        ]);
+        let ObjType::Tree { size, objs, .. } =
+            GitObject::try_from_bytes(&read_object(&sha).unwrap())
+                .unwrap()
+                .obj_type
+        else {
+            panic!("{} is not a tree", sha)
+        };
+        let mut listed = format!("tree {} (SHA: NONE)\n", size);
+        for o in &objs {
+            listed.push_str(&format!("{}\n", o.to_full_string(false, None)));
+        }
+
+        assert_eq!(mk_tree(listed.as_bytes(), false).unwrap(), sha);
+    }
+
+    #[test]
+    fn mk_tree_refuses_names_out_of_the_work_tree() {
+        temp_repo("mk-tree-names");
+        let a = blob("one");
+        for name in [".", "..", ".idiot", ".git", "a/b"] {
+            let line = format!("100644 blob {}\t{}\n", a, name);
+            let err = mk_tree(line.as_bytes(), false).unwrap_err();
+            assert!(
+                format!("{:#}", err).contains("invalid tree entry name"),
+                "{}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn batch_all_objects_reports_bad_objects_instead_of_missing() {
+        temp_repo("batch-all-objects");
+        let a = blob("one");
+        let odd = store::write_object(b"odd 3\0one", false).unwrap();
+        store::write_object(b"junk", false).unwrap();
+
+        let mut out = vec![];
+        assert!(!batch_check_all(false, &mut out).unwrap());
+        assert_eq!(String::from_utf8(out).unwrap(), format!("{} blob 3\n", a));
+
+        let mut out = vec![];
+        assert!(!batch_check_all(true, &mut out).unwrap());
+        let mut expected = [format!("{} blob 3\n", a), format!("{} odd 3\n", odd)];
+        expected.sort();
+        assert_eq!(String::from_utf8(out).unwrap(), expected.concat());
+    }
+
+    #[test]
+    fn log_level_leaves_subcommand_verbose_alone() {
+        let args =
+            Idiot::try_parse_from(["idiot", "--log-level=debug", "write-tree", "-v"]).unwrap();
+        assert!(matches!(args.log_level, Some(log::Level::Debug)));
+        assert!(matches!(
+            args.command,
+            Command::WriteTree { verbose: true, .. }
+        ));
+
+        let args =
+            Idiot::try_parse_from(["idiot", "write-tree", "-v", "--log-level", "info"]).unwrap();
+        assert!(matches!(args.log_level, Some(log::Level::Info)));
+    }
+
+    #[test]
+    fn objects_compressed_at_any_level_read_back() {
+        temp_repo("zlib-levels");
+        let content = "the same bytes at every level";
+        let obj = format!("blob {}\0{}", content.len(), content).into_bytes();
+        let sha = hex::encode(Sha1::digest(&obj));
+        let path = store::object_path(&sha).unwrap();
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        for level in 0..=9 {
+            let mut compressed = vec![];
+            ZlibEncoder::new(obj.as_slice(), Compression::new(level))
+                .read_to_end(&mut compressed)
+                .unwrap();
+            assert_eq!(decomp_obj(&compressed).unwrap(), obj, "level {}", level);
+            fs::write(&path, &compressed).unwrap();
+            assert_eq!(read_object(&sha).unwrap(), obj, "level {}", level);
+        }
+    }
+
+    #[test]
+    fn bytes_after_the_zlib_stream_are_ignored() {
+        temp_repo("zlib-trailing");
+        let obj = b"blob 5\0hello";
+        let sha = hex::encode(Sha1::digest(obj));
+        let mut compressed = compress_obj(obj, false).unwrap();
+        compressed.extend(b"\0\0\0padding after the stream");
+
+        assert_eq!(decomp_obj(&compressed).unwrap(), obj);
+        let mut out = vec![];
+        assert_eq!(decomp_to(compressed.as_slice(), &mut out).unwrap(), 12);
+        assert_eq!(out, obj);
+        let path = store::object_path(&sha).unwrap();
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, &compressed).unwrap();
+        assert_eq!(read_object(&sha).unwrap(), obj);
+        assert_eq!(store::read_blob(&sha).unwrap(), b"hello");
+    }
+
+    #[test]
+    fn textconv_runs_the_diff_driver_of_the_path() {
+        let top = temp_repo("textconv");
+        fs::write(top.join(".gitattributes"), "*.hex diff=hex\n").unwrap();
+        config::Config::append_section("diff", "hex", &[("textconv", "cat")]).unwrap();
+
+        assert_eq!(textconv_command("HEAD:a.hex").as_deref(), Some("cat"));
+        assert_eq!(textconv_command("HEAD:./dir/b.hex").as_deref(), Some("cat"));
+        assert_eq!(textconv_command("HEAD:a.txt"), None);
+        assert_eq!(textconv_command(&blob("hello")), None);
+        assert_eq!(run_textconv("cat", &b"hello"[..]).unwrap(), b"hello");
+        assert_eq!(run_textconv("tr l L <", &b"hello"[..]).unwrap(), b"heLLo");
+        assert!(run_textconv("false", &b"hello"[..]).is_err());
+    }
+
+    #[test]
+    fn write_tree_from_the_index_checks_blobs_exist() {
+        temp_repo("write-tree-index");
+        let a = blob("one");
+        let missing = "0123456789abcdef0123456789abcdef01234567";
+        let entry = |path: &str, sha: &str| IndexEntry {
+            mode: Mode::FileBlob,
+            sha: hex::decode(sha).unwrap().try_into().unwrap(),
+            stage: 0,
+            mtime: (0, 0),
+            size: 0,
+            path: path.to_string(),
+        };
+        index::write_index(&[entry("a", &a), entry("dir/b", missing), entry("dir/c", &a)]).unwrap();
+
+        let err = idiot(&["write-tree", "--index"]).unwrap_err();
+        let msg = format!("{:#}", err);
+        assert!(
+            msg.contains(&format!("invalid object 100644 {} for 'dir/b'", missing)),
+            "{}",
+            msg
+        );
+        assert!(idiot(&["write-tree", "--missing-ok"]).is_err());
+        assert_eq!(
+            idiot(&["write-tree", "--index", "--missing-ok"]).unwrap(),
+            ExitCode::SUCCESS
+        );
+
+        let dir = tree(&[(Mode::FileBlob, "b", missing), (Mode::FileBlob, "c", &a)]);
+        let top = tree(&[(Mode::FileBlob, "a", &a), (Mode::SubDir, "dir", &dir)]);
+        let entries = index::read_index().unwrap();
+        assert_eq!(write_index_tree(&entries, "", true, false).unwrap(), top);
+    }
+
+    #[test]
+    fn clean_deletes_only_untracked_files_with_force() {
+        let top = temp_repo("clean");
+        let ignore = blob("*.log\n");
+        let files = tree(&[
+            (Mode::FileBlob, ".gitignore", &ignore),
+            (Mode::FileBlob, "a", &blob("a")),
+        ]);
+        idiot(&["reset", "--hard", &commit(&files, &[], "first")]).unwrap();
+        for path in ["b", "dir/c", "dir/deeper/d", "x.log", "sub/e"] {
+            fs::create_dir_all(top.join(path).parent().unwrap()).unwrap();
+            fs::write(top.join(path), path).unwrap();
+        }
+        init_repo(&top.join("sub").join(IDIOT)).unwrap();
+        repo::open(&top.join(IDIOT));
+
+        let mut untracked = vec![];
+        let tracked = index_paths().into_iter().collect();
+        untracked_files(&top, "", &Ignore::from_root(&top), &tracked, &mut untracked).unwrap();
+        untracked.sort();
+        assert_eq!(untracked, ["b", "dir/c", "dir/deeper/d"]);
+
+        idiot(&["clean"]).unwrap();
+        idiot(&["clean", "-f", "-n"]).unwrap();
+        assert!(top.join("b").exists() && top.join("dir/deeper/d").exists());
+        idiot(&["clean", "-f"]).unwrap();
+        assert!(!top.join("b").exists() && !top.join("dir").exists());
+        assert!(top.join("a").exists() && top.join(".gitignore").exists());
+        assert!(top.join("x.log").exists() && top.join("sub/e").exists());
+        idiot(&["clean", "-f", "-x"]).unwrap();
+        assert!(!top.join("x.log").exists() && top.join("a").exists());
+        assert!(top.join("sub/e").exists());
+    }
+
+    #[test]
+    fn trees_with_a_tombstone_are_not_written() {
+        temp_repo("tombstone-tree");
+        let deleted = GitObject {
+            mode: Mode::FileBlob,
+            obj_type: ObjType::Blob {
+                path: "gone".to_string(),
+                content: vec![],
+            },
+            sha: None,
+        };
+        let err = write_tree_entries(vec![deleted], false).unwrap_err();
+        assert!(err.to_string().contains("it is being deleted"));
+        assert_eq!(store::iter_loose_objects(&repo::objects()).count(), 0);
+    }
 }
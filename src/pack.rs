@@ -0,0 +1,406 @@
+//! Version 2 packfiles and their `.idx` indexes, see `gitformat-pack(5)`.
+use std::{
+    collections::{HashMap, HashSet},
+    io::Write,
+};
+
+use anyhow::Context;
+use flate2::{write::ZlibEncoder, Compression, Crc, Decompress, FlushDecompress, Status};
+use sha1::{Digest, Sha1};
+
+use crate::store::read_object;
+
+/// Where one object ended up in a pack, what the `.idx` is built from.
+struct PackedObject {
+    sha: [u8; 20],
+    crc: u32,
+    offset: usize,
+}
+
+/// The type number a pack entry header uses for `kind`.
+fn type_code(kind: &str) -> anyhow::Result<u8> {
+    Ok(match kind {
+        "commit" => 1,
+        "tree" => 2,
+        "blob" => 3,
+        "tag" => 4,
+        _ => anyhow::bail!("can't pack an object of type '{}'", kind),
+    })
+}
+
+/// The object type a pack entry header's type number stands for.
+fn type_name(code: u8) -> anyhow::Result<&'static str> {
+    Ok(match code {
+        1 => "commit",
+        2 => "tree",
+        3 => "blob",
+        4 => "tag",
+        _ => anyhow::bail!("unknown pack object type {}", code),
+    })
+}
+
+/// Pack the objects `shas` whole, without deltas, returns the pack and its `.idx`.
+///
+/// The pack's checksum is the last 20 bytes of both. An object named more than once is
+/// only packed once, the header and the `.idx` have to agree on how many there are.
+pub fn write_pack(shas: &[String]) -> anyhow::Result<(Vec<u8>, Vec<u8>)> {
+    let mut seen = HashSet::new();
+    let shas = shas
+        .iter()
+        .filter(|sha| seen.insert(sha.to_ascii_lowercase()))
+        .collect::<Vec<_>>();
+    let mut pack = b"PACK".to_vec();
+    pack.extend(2u32.to_be_bytes());
+    pack.extend(u32::try_from(shas.len())?.to_be_bytes());
+
+    let mut packed = Vec::with_capacity(shas.len());
+    for sha in shas {
+        let obj = read_object(sha)?;
+        let nul = obj
+            .iter()
+            .position(|b| *b == b'\0')
+            .with_context(|| format!("object {} has no header", sha))?;
+        let (kind, _) = crate::parse_header(&obj[..=nul])?;
+        let content = &obj[nul + 1..];
+
+        let offset = pack.len();
+        // Type and size: 3 type bits and the low 4 size bits, then 7 size bits a byte
+        let mut size = content.len();
+        let mut byte = (type_code(&kind)? << 4) | (size & 0x0f) as u8;
+        size >>= 4;
+        while size != 0 {
+            pack.push(byte | 0x80);
+            byte = (size & 0x7f) as u8;
+            size >>= 7;
+        }
+        pack.push(byte);
+        let mut enc = ZlibEncoder::new(&mut pack, Compression::default());
+        enc.write_all(content)?;
+        enc.finish()?;
+
+        let mut crc = Crc::new();
+        crc.update(&pack[offset..]);
+        packed.push(PackedObject {
+            sha: hex::decode(sha)?.try_into().unwrap(),
+            crc: crc.sum(),
+            offset,
+        });
+    }
+    let checksum = Sha1::digest(&pack);
+    pack.extend(checksum);
+
+    let idx = write_pack_index(packed, &checksum)?;
+    Ok((pack, idx))
+}
+
+/// Build a version 2 `.idx` for `packed` in a pack with the checksum `pack_sha`.
+fn write_pack_index(mut packed: Vec<PackedObject>, pack_sha: &[u8]) -> anyhow::Result<Vec<u8>> {
+    packed.sort_by_key(|p| p.sha);
+
+    let mut idx = b"\xfftOc".to_vec();
+    idx.extend(2u32.to_be_bytes());
+    // How many objects start with a byte less than or equal to each first byte
+    for first in 0..=255u8 {
+        let count = packed.partition_point(|p| p.sha[0] <= first);
+        idx.extend(u32::try_from(count)?.to_be_bytes());
+    }
+    for p in &packed {
+        idx.extend(p.sha);
+    }
+    for p in &packed {
+        idx.extend(p.crc.to_be_bytes());
+    }
+    // Offsets past 2GiB go in a table of 8-byte offsets, pointed at with the high bit
+    let mut large = vec![];
+    for p in &packed {
+        match u32::try_from(p.offset) {
+            Ok(offset) if offset & 0x8000_0000 == 0 => idx.extend(offset.to_be_bytes()),
+            _ => {
+                idx.extend((0x8000_0000 | u32::try_from(large.len())?).to_be_bytes());
+                large.push(p.offset as u64);
+            }
+        }
+    }
+    for offset in large {
+        idx.extend(offset.to_be_bytes());
+    }
+    idx.extend(pack_sha);
+    let checksum = Sha1::digest(&idx);
+    idx.extend(checksum);
+    Ok(idx)
+}
+
+/// Every object in `pack` as `(type, content)` in pack order, after checking the pack's
+/// checksum.
+///
+/// Deltas are resolved against objects earlier in the pack, or for `REF_DELTA` against
+/// the object store too.
+pub fn read_pack(pack: &[u8]) -> anyhow::Result<Vec<(String, Vec<u8>)>> {
+    anyhow::ensure!(pack.len() >= 12 + 20, "pack is too short");
+    let (content, checksum) = pack.split_at(pack.len() - 20);
+    anyhow::ensure!(
+        Sha1::digest(content).as_slice() == checksum,
+        "pack checksum mismatch"
+    );
+    anyhow::ensure!(&content[..4] == b"PACK", "pack has a bad signature");
+    let version = u32::from_be_bytes(content[4..8].try_into().unwrap());
+    anyhow::ensure!(
+        version == 2 || version == 3,
+        "pack version {} is not supported",
+        version
+    );
+    let count = u32::from_be_bytes(content[8..12].try_into().unwrap()) as usize;
+
+    // The count is only as good as the pack, grow as entries are actually read
+    let mut objects: Vec<(String, Vec<u8>)> = vec![];
+    // Where each object starts and its SHA, the two ways a delta names its base
+    let mut by_offset: HashMap<usize, usize> = HashMap::new();
+    let mut by_sha: HashMap<String, usize> = HashMap::new();
+    let mut at = 12;
+    for _ in 0..count {
+        let offset = at;
+        let mut byte = *content.get(at).context("pack entry is cut short")?;
+        at += 1;
+        let code = (byte >> 4) & 0b111;
+        let mut size = (byte & 0x0f) as usize;
+        let mut shift = 4;
+        while byte & 0x80 != 0 {
+            anyhow::ensure!(shift < usize::BITS, "pack entry size is too large");
+            byte = *content.get(at).context("pack entry is cut short")?;
+            at += 1;
+            size |= ((byte & 0x7f) as usize) << shift;
+            shift += 7;
+        }
+
+        let base = match code {
+            // OFS_DELTA, the base is a (weirdly encoded) distance back from this entry
+            6 => {
+                let mut byte = *content.get(at).context("pack entry is cut short")?;
+                at += 1;
+                let mut distance = (byte & 0x7f) as usize;
+                while byte & 0x80 != 0 {
+                    byte = *content.get(at).context("pack entry is cut short")?;
+                    at += 1;
+                    distance = distance
+                        .checked_add(1)
+                        .and_then(|d| d.checked_mul(0x80))
+                        .context("delta base is before the pack")?
+                        | (byte & 0x7f) as usize;
+                }
+                let base_offset = offset
+                    .checked_sub(distance)
+                    .context("delta base is before the pack")?;
+                let idx = by_offset
+                    .get(&base_offset)
+                    .with_context(|| format!("no object at offset {} in pack", base_offset))?;
+                Some(objects[*idx].clone())
+            }
+            // REF_DELTA, the base is named by its SHA
+            7 => {
+                let sha = hex::encode(
+                    content
+                        .get(at..at + 20)
+                        .context("pack entry is cut short")?,
+                );
+                at += 20;
+                match by_sha.get(&sha) {
+                    Some(idx) => Some(objects[*idx].clone()),
+                    None => {
+                        let obj = read_object(&sha)
+                            .with_context(|| format!("delta base {} is missing", sha))?;
+                        let nul = obj.iter().position(|b| *b == b'\0').unwrap_or_default();
+                        let (kind, _) = crate::parse_header(&obj[..=nul])?;
+                        Some((kind, obj[nul + 1..].to_vec()))
+                    }
+                }
+            }
+            _ => None,
+        };
+
+        let (data, used) = inflate(&content[at..], size)?;
+        at += used;
+        let (kind, data) = match base {
+            Some((kind, base)) => {
+                let data = apply_delta(&base, &data).context("applying delta")?;
+                (kind, data)
+            }
+            None => (type_name(code)?.to_string(), data),
+        };
+
+        let mut hasher = Sha1::new();
+        hasher.update(format!("{} {}\0", kind, data.len()));
+        hasher.update(&data);
+        by_sha.insert(hex::encode(hasher.finalize()), objects.len());
+        by_offset.insert(offset, objects.len());
+        objects.push((kind, data));
+    }
+    anyhow::ensure!(at == content.len(), "pack has trailing garbage");
+    Ok(objects)
+}
+
+/// Inflate the zlib stream at the start of `data` which should hold `size` bytes, returns
+/// them and how many bytes of `data` the stream took.
+fn inflate(data: &[u8], size: usize) -> anyhow::Result<(Vec<u8>, usize)> {
+    let mut decomp = Decompress::new(true);
+    // The size is only as good as the pack, the buffer grows as the stream fills it
+    let mut out = Vec::with_capacity(size.min(data.len()).saturating_add(1));
+    loop {
+        if out.len() == out.capacity() {
+            out.reserve(out.len().max(64));
+        }
+        // Not `Finish`, that wants room for the whole output on the first call
+        let (used, written) = (decomp.total_in(), decomp.total_out());
+        let status = decomp
+            .decompress_vec(&data[used as usize..], &mut out, FlushDecompress::None)
+            .context("inflating pack entry")?;
+        if matches!(status, Status::StreamEnd) {
+            break;
+        }
+        // There was room for output, so no progress means the input ran out
+        anyhow::ensure!(
+            decomp.total_in() > used || decomp.total_out() > written,
+            "pack entry is cut short"
+        );
+        anyhow::ensure!(out.len() <= size, "pack entry is more than {} bytes", size);
+    }
+    anyhow::ensure!(
+        out.len() == size,
+        "pack entry is {} bytes, expected {}",
+        out.len(),
+        size
+    );
+    Ok((out, decomp.total_in() as usize))
+}
+
+/// Rebuild an object from `base` and a git delta, a list of copies from the base and
+/// inserts of new bytes.
+fn apply_delta(base: &[u8], delta: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let mut at = 0;
+    let mut varint = || -> anyhow::Result<usize> {
+        let mut n = 0;
+        let mut shift = 0;
+        loop {
+            anyhow::ensure!(shift < usize::BITS, "delta size is too large");
+            let byte = *delta.get(at).context("delta is cut short")?;
+            at += 1;
+            n |= ((byte & 0x7f) as usize) << shift;
+            shift += 7;
+            if byte & 0x80 == 0 {
+                return Ok(n);
+            }
+        }
+    };
+    let base_size = varint()?;
+    let size = varint()?;
+    anyhow::ensure!(base_size == base.len(), "delta base has the wrong size");
+
+    // Copies can repeat the base, but the size is only a hint until the delta is applied
+    let mut out = Vec::with_capacity(size.min(base.len() + delta.len()));
+    while let Some(&op) = delta.get(at) {
+        at += 1;
+        if op & 0x80 != 0 {
+            // Copy, the low 4 bits say which offset bytes follow, the next 3 size bytes
+            let mut fields = [0usize; 2];
+            for (bit, field) in (0..7).map(|bit| (bit, if bit < 4 { 0 } else { 1 })) {
+                if op & (1 << bit) != 0 {
+                    let byte = *delta.get(at).context("delta is cut short")?;
+                    at += 1;
+                    fields[field] |= (byte as usize) << (8 * (bit % 4));
+                }
+            }
+            let [offset, len] = fields;
+            let len = if len == 0 { 0x10000 } else { len };
+            let copy = base
+                .get(offset..offset + len)
+                .context("delta copies past its base")?;
+            out.extend_from_slice(copy);
+        } else if op != 0 {
+            let insert = delta
+                .get(at..at + op as usize)
+                .context("delta is cut short")?;
+            out.extend_from_slice(insert);
+            at += op as usize;
+        } else {
+            anyhow::bail!("delta has a reserved instruction");
+        }
+        anyhow::ensure!(out.len() <= size, "delta result has the wrong size");
+    }
+    anyhow::ensure!(out.len() == size, "delta result has the wrong size");
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{blob, commit, temp_repo, tree};
+
+    /// A pack of `entries` with a header claiming `count` objects and a good checksum.
+    fn pack_of(count: u32, entries: &[u8]) -> Vec<u8> {
+        let mut pack = b"PACK".to_vec();
+        pack.extend(2u32.to_be_bytes());
+        pack.extend(count.to_be_bytes());
+        pack.extend(entries);
+        let checksum = Sha1::digest(&pack);
+        pack.extend(checksum);
+        pack
+    }
+
+    #[test]
+    fn read_pack_reads_what_write_pack_wrote() {
+        temp_repo("pack-round-trip");
+        let (pack, _) = write_pack(&[blob("one")]).unwrap();
+        let objects = read_pack(&pack).unwrap();
+        assert_eq!(objects, [("blob".to_string(), b"one".to_vec())]);
+    }
+
+    #[test]
+    fn write_pack_packs_each_object_once() {
+        temp_repo("pack-objects");
+        let a = blob("one");
+        let root = tree(&[(crate::tree::Mode::FileBlob, "a", &a)]);
+        let first = commit(&root, &[], "first");
+        let shas = [a.clone(), root.clone(), first.clone(), a.to_uppercase()];
+
+        let (pack, idx) = write_pack(&shas).unwrap();
+        assert_eq!(&pack[8..12], 3u32.to_be_bytes());
+        // The last fanout entry is how many objects the index has
+        assert_eq!(&idx[8 + 255 * 4..8 + 256 * 4], 3u32.to_be_bytes());
+        let shas = read_pack(&pack)
+            .unwrap()
+            .into_iter()
+            .map(|(kind, content)| {
+                let mut obj = format!("{} {}\0", kind, content.len()).into_bytes();
+                obj.extend(content);
+                hex::encode(Sha1::digest(&obj))
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(shas, [a, root, first]);
+    }
+
+    #[test]
+    fn read_pack_doesnt_trust_the_object_count() {
+        let err = read_pack(&pack_of(u32::MAX, &[])).unwrap_err();
+        assert!(format!("{:#}", err).contains("cut short"), "{:#}", err);
+    }
+
+    #[test]
+    fn read_pack_refuses_an_endless_size() {
+        let err = read_pack(&pack_of(1, &[0xff; 16])).unwrap_err();
+        assert!(format!("{:#}", err).contains("too large"), "{:#}", err);
+    }
+
+    #[test]
+    fn read_pack_refuses_a_truncated_stream() {
+        temp_repo("pack-truncated");
+        let (pack, _) = write_pack(&[blob("a blob that takes a few bytes to deflate")]).unwrap();
+        // Drop the end of the zlib stream and the checksum, keeping the header and size
+        let err = read_pack(&pack_of(1, &pack[12..pack.len() - 30])).unwrap_err();
+        assert!(format!("{:#}", err).contains("cut short"), "{:#}", err);
+    }
+
+    #[test]
+    fn apply_delta_refuses_an_endless_size() {
+        let err = apply_delta(b"", &[0xff; 16]).unwrap_err();
+        assert!(format!("{:#}", err).contains("too large"), "{:#}", err);
+    }
+}
@@ -0,0 +1,64 @@
+use std::{
+    io::{self, IsTerminal, Write},
+    time::{Duration, Instant},
+};
+
+/// How long a run has to take before progress shows up on its own.
+const DELAY: Duration = Duration::from_secs(1);
+/// How often the count is redrawn.
+const INTERVAL: Duration = Duration::from_millis(100);
+
+/// A count of work done, redrawn on one stderr line like git's progress meters.
+///
+/// Stdout is never touched, it carries the command's real output.
+#[derive(Debug)]
+pub struct Progress {
+    title: &'static str,
+    count: usize,
+    start: Instant,
+    /// When it last drew, `None` until it has drawn once.
+    drawn: Option<Instant>,
+    /// Drawn from the start when asked for, otherwise only on a terminal once past `DELAY`.
+    forced: bool,
+    enabled: bool,
+}
+
+impl Progress {
+    /// With `forced` progress always shows, otherwise only when stderr is a terminal and
+    /// `quiet` isn't set.
+    pub fn new(title: &'static str, forced: bool, quiet: bool) -> Self {
+        Self {
+            title,
+            count: 0,
+            start: Instant::now(),
+            drawn: None,
+            forced,
+            enabled: forced || (!quiet && io::stderr().is_terminal()),
+        }
+    }
+
+    /// Count one more item done.
+    pub fn tick(&mut self) {
+        self.count += 1;
+        if !self.enabled {
+            return;
+        }
+        let now = Instant::now();
+        let due = match self.drawn {
+            Some(last) => now - last >= INTERVAL,
+            None => self.forced || now - self.start >= DELAY,
+        };
+        if due {
+            self.drawn = Some(now);
+            eprint!("\r{}: {}", self.title, self.count);
+            let _ = io::stderr().flush();
+        }
+    }
+
+    /// Draw the final count, only if anything was drawn before (or it was asked for).
+    pub fn finish(&mut self) {
+        if self.enabled && (self.forced || self.drawn.is_some()) {
+            eprintln!("\r{}: {}, done.", self.title, self.count);
+        }
+    }
+}
@@ -0,0 +1,70 @@
+//! Where the repository is: `.idiot` at the top of the work tree, or the directory itself
+//! for a bare repository.
+use std::{
+    cell::RefCell,
+    env,
+    path::{Path, PathBuf},
+};
+
+use crate::{log, IDIOT};
+
+thread_local! {
+    /// The absolute path of the repository commands work on, set by [`open`]. Kept per
+    /// thread so tests running side by side can each have their own.
+    static DIR: RefCell<Option<PathBuf>> = const { RefCell::new(None) };
+}
+
+/// Find the repository for the work tree (or bare repository) at `top`.
+///
+/// Without a `.idiot` the directory is taken as a bare repository when it looks like one
+/// (`HEAD`, `objects/` and `refs/` at the top), otherwise it is where `.idiot` would go.
+pub fn discover(top: &Path) -> PathBuf {
+    let bare = !top.join(IDIOT).is_dir()
+        && top.join("HEAD").is_file()
+        && top.join("objects").is_dir()
+        && top.join("refs").is_dir();
+    if bare {
+        top.to_path_buf()
+    } else {
+        top.join(IDIOT)
+    }
+}
+
+/// Work on the repository at `dir`, its `.idiot` or a bare repository, from here on.
+pub fn open(dir: &Path) {
+    let dir = env::current_dir().map_or(dir.to_path_buf(), |cwd| cwd.join(dir));
+    log::debug!("using the repository in '{}'", dir.display());
+    DIR.with(|d| *d.borrow_mut() = Some(dir));
+}
+
+/// The repository directory, the one in the current directory unless [`open`] said
+/// otherwise.
+pub fn dir() -> PathBuf {
+    if let Some(dir) = DIR.with(|d| d.borrow().clone()) {
+        return dir;
+    }
+    open(&discover(&env::current_dir().unwrap_or_default()));
+    dir()
+}
+
+/// The loose object directory, `<repo>/objects`.
+pub fn objects() -> PathBuf {
+    dir().join("objects")
+}
+
+pub fn is_bare() -> bool {
+    dir().file_name() != Some(IDIOT.as_ref())
+}
+
+/// The top of the work tree, where `.idiot` is, an error in a bare repository.
+pub fn work_tree() -> anyhow::Result<PathBuf> {
+    require_work_tree()?;
+    let dir = dir();
+    Ok(dir.parent().unwrap_or(&dir).to_path_buf())
+}
+
+/// Fail for commands that read or write a work tree when there is none.
+pub fn require_work_tree() -> anyhow::Result<()> {
+    anyhow::ensure!(!is_bare(), "this operation must be run in a work tree");
+    Ok(())
+}
@@ -0,0 +1,659 @@
+use std::{fs, io::Write, path::Path};
+
+use anyhow::Context;
+
+use crate::{
+    repo,
+    store::{read_blob, read_object, FANOUT_LEN},
+    tree::{normalize_repo_path, GitObject, Mode, ObjType, EMPTY_TREE_SHA},
+};
+
+/// Where a short name is looked for, in order, see `gitrevisions(7)`.
+const REF_RULES: [&str; 6] = [
+    "{}",
+    "refs/{}",
+    "refs/tags/{}",
+    "refs/heads/{}",
+    "refs/remotes/{}",
+    "refs/remotes/{}/HEAD",
+];
+
+/// Resolve `rev` (a ref name, a full SHA or an unambiguous SHA prefix) to the full hex SHA
+/// of an object in the store.
+///
+/// A `^{<type>}` suffix peels the object until it is of that type (a tag to what it tags,
+/// a commit to its tree), `^{}` peels tags only.
+///
+/// `<rev>:<path>` is the entry at `path` in the revision's tree, `path` is from the top
+/// of the tree unless it starts with `./` or `../`, then it is from the current directory.
+pub fn resolve(rev: &str) -> anyhow::Result<String> {
+    resolve_with(rev, false)
+}
+
+/// Like [`resolve`], with `follow_symlinks` symlinks in a `<rev>:<path>` path are followed
+/// within the revision's tree instead of naming the link itself.
+pub fn resolve_with(rev: &str, follow_symlinks: bool) -> anyhow::Result<String> {
+    if let Some((base, path)) = rev.split_once(':') {
+        anyhow::ensure!(
+            !base.is_empty(),
+            "reading '{}' from the index is not supported",
+            rev
+        );
+        let tree = peel(&resolve(base)?, Some("tree"))?;
+        let path = if path.starts_with("./") || path.starts_with("../") {
+            normalize_repo_path(path)?
+        } else {
+            path.to_string()
+        };
+        return lookup(&tree, &path, follow_symlinks)
+            .with_context(|| format!("can't find '{}' in '{}'", path, base));
+    }
+    if let Some((base, kind)) = rev.strip_suffix('}').and_then(|rev| rev.rsplit_once("^{")) {
+        let sha = resolve(base)?;
+        return peel(&sha, (!kind.is_empty()).then_some(kind))
+            .with_context(|| format!("can't peel '{}'", rev));
+    }
+
+    if let Some(sha) = resolve_ref(rev)? {
+        return Ok(sha);
+    }
+    if rev.len() >= 4 && rev.len() <= 40 && rev.bytes().all(|ch| ch.is_ascii_hexdigit()) {
+        return resolve_abbrev(&rev.to_ascii_lowercase());
+    }
+    anyhow::bail!("unknown revision '{}'", rev)
+}
+
+/// Follow `sha` through tags (and from a commit to its tree) until reaching an object of
+/// type `kind`, with no `kind` stop at the first object that isn't a tag.
+pub fn peel(sha: &str, kind: Option<&str>) -> anyhow::Result<String> {
+    let obj = read_object(sha)?;
+    let obj_kind = obj
+        .split(|ch| ch == &b' ')
+        .next()
+        .map(String::from_utf8_lossy)
+        .unwrap_or_default();
+    if kind.map_or(obj_kind != "tag", |kind| kind == obj_kind) {
+        return Ok(sha.to_string());
+    }
+
+    if obj_kind == "tag" {
+        // Only the `object <sha>` line matters, it is always the first one
+        let body = obj.splitn(2, |ch| ch == &b'\0').nth(1).unwrap_or_default();
+        let object = String::from_utf8_lossy(body)
+            .lines()
+            .next()
+            .and_then(|line| line.strip_prefix("object "))
+            .map(str::to_string)
+            .with_context(|| format!("tag {} has no object", sha))?;
+        return peel(&object, kind);
+    }
+    if obj_kind == "commit" && kind == Some("tree") {
+        if let ObjType::Commit { tree, .. } = GitObject::try_from_bytes(&obj)?.obj_type {
+            return Ok(tree);
+        }
+    }
+    anyhow::bail!("{} is a {}, not a {}", sha, obj_kind, kind.unwrap_or("tag"))
+}
+
+/// The SHA of the entry at `path` (`/` separated, from the top) in the tree `tree_sha`,
+/// an empty path is the tree itself.
+pub fn lookup_path(tree_sha: &str, path: &str) -> anyhow::Result<String> {
+    lookup(tree_sha, path, false)
+}
+
+/// How many symlinks [`lookup`] follows for one path before taking it for a loop, git's limit.
+const MAX_SYMLINKS: usize = 40;
+
+/// Find `path` in the tree `tree_sha`, with `follow_symlinks` a symlink on the way (or at
+/// the end) is followed to what it points at inside the same tree, like git's
+/// `cat-file --follow-symlinks`.
+fn lookup(tree_sha: &str, path: &str, follow_symlinks: bool) -> anyhow::Result<String> {
+    let mut path = path.to_string();
+    for _ in 0..=MAX_SYMLINKS {
+        let mut parts: Vec<&str> = vec![];
+        for part in path
+            .split('/')
+            .filter(|part| !part.is_empty() && *part != ".")
+        {
+            // A link's target is relative to the link, `..` can only be resolved by name
+            // once one was followed
+            if part == ".." && follow_symlinks {
+                anyhow::ensure!(parts.pop().is_some(), "'{}' points outside the tree", path);
+            } else {
+                parts.push(part);
+            }
+        }
+
+        let mut sha = tree_sha.to_string();
+        let mut is_tree = true;
+        let mut link = None;
+        for (at, part) in parts.iter().enumerate() {
+            let walked = parts[..at].join("/");
+            // Only subtrees can be walked into, a gitlink's commit isn't even in this repo
+            anyhow::ensure!(is_tree, "path '{}' is not a directory", walked);
+            let ObjType::Tree { objs, .. } =
+                GitObject::try_from_bytes(&read_object(&sha)?)?.obj_type
+            else {
+                anyhow::bail!("object {} at '{}' is not a tree", sha, walked)
+            };
+            let entry = objs
+                .iter()
+                .find(|o| o.as_path_str() == *part)
+                .with_context(|| format!("path '{}' does not exist", parts[..=at].join("/")))?;
+            sha = hex::encode(entry.sha.as_ref().expect("tree entries have a sha"));
+            is_tree = matches!(entry.mode, Mode::SubDir);
+            if follow_symlinks && matches!(entry.mode, Mode::SymLink) {
+                link = Some(at);
+                break;
+            }
+        }
+        let Some(at) = link else {
+            return Ok(sha);
+        };
+        let target = String::from_utf8(read_blob(&sha)?).context("symlink target isn't UTF-8")?;
+        anyhow::ensure!(
+            !target.starts_with('/'),
+            "symlink '{}' points outside the tree",
+            parts[..=at].join("/")
+        );
+        path = parts[..at]
+            .iter()
+            .copied()
+            .chain([target.as_str()])
+            .chain(parts[at + 1..].iter().copied())
+            .collect::<Vec<_>>()
+            .join("/");
+    }
+    anyhow::bail!("too many levels of symlinks at '{}'", path)
+}
+
+/// Every ref under `<repo>/<prefix>` as `(name, sha)` sorted by name, names are relative to
+/// the repo directory like `refs/tags/v1.0`.
+///
+/// Packed refs are included unless a loose ref of the same name overrides them.
+pub fn list_refs(prefix: &str) -> anyhow::Result<Vec<(String, String)>> {
+    let prefix = prefix.trim_end_matches('/');
+    let mut refs = vec![];
+    let mut dirs = vec![prefix.to_string()];
+    while let Some(dir) = dirs.pop() {
+        let Ok(entries) = fs::read_dir(repo::dir().join(&dir)) else {
+            continue;
+        };
+        for e in entries {
+            let e = e?;
+            let name = format!("{}/{}", dir, e.file_name().to_string_lossy());
+            if e.file_type()?.is_dir() {
+                dirs.push(name);
+            } else {
+                let sha = read_ref(&name)?;
+                refs.push((name, sha));
+            }
+        }
+    }
+    for (name, sha) in packed_refs()? {
+        let under_prefix = name
+            .strip_prefix(prefix)
+            .is_some_and(|rest| rest.starts_with('/'));
+        if under_prefix && !refs.iter().any(|(loose, _)| *loose == name) {
+            refs.push((name, sha));
+        }
+    }
+    refs.sort();
+    Ok(refs)
+}
+
+/// The refs in `<repo>/packed-refs` as `(name, sha)`, where git moves refs to save a
+/// file each. The `^<sha>` lines git adds after annotated tags are skipped, the tag is
+/// peeled when it is needed.
+fn packed_refs() -> anyhow::Result<Vec<(String, String)>> {
+    let Ok(content) = fs::read_to_string(repo::dir().join("packed-refs")) else {
+        return Ok(vec![]);
+    };
+    let mut refs = vec![];
+    for line in content.lines() {
+        if line.starts_with('#') || line.starts_with('^') || line.is_empty() {
+            continue;
+        }
+        let (sha, name) = line
+            .split_once(' ')
+            .with_context(|| format!("bad packed-refs line '{}'", line))?;
+        refs.push((name.to_string(), sha.to_string()));
+    }
+    Ok(refs)
+}
+
+/// Move every loose ref under `refs/` into `packed-refs` (with the peeled commit of
+/// annotated tags) and delete the loose files, returning how many refs were packed.
+///
+/// Symbolic refs stay loose, like `HEAD`.
+pub fn pack_refs() -> anyhow::Result<usize> {
+    let mut packed = 0;
+    let mut content = "# pack-refs with: peeled fully-peeled sorted \n".to_string();
+    let mut loose = vec![];
+    for (name, sha) in list_refs("refs")? {
+        let path = repo::dir().join(&name);
+        if let Ok(file) = fs::read_to_string(&path) {
+            if file.starts_with("ref: ") {
+                continue;
+            }
+            loose.push(path);
+        }
+        content.push_str(&format!("{} {}\n", sha, name));
+        let peeled = peel(&sha, None)?;
+        if peeled != sha {
+            content.push_str(&format!("^{}\n", peeled));
+        }
+        packed += 1;
+    }
+
+    // Written aside and renamed so no ref is ever missing from both places
+    let lock = repo::dir().join("packed-refs.lock");
+    fs::write(&lock, content).context("writing packed-refs")?;
+    fs::rename(&lock, repo::dir().join("packed-refs")).context("writing packed-refs")?;
+    for path in loose {
+        fs::remove_file(&path)?;
+        prune_ref_dirs(&path);
+    }
+    Ok(packed)
+}
+
+/// Drop the directories above the removed loose ref at `path` that are left empty, down
+/// to but not including `refs/heads` and the like.
+fn prune_ref_dirs(path: &Path) {
+    let top = repo::dir().join("refs");
+    let mut dir = path.parent();
+    while let Some(d) = dir.filter(|d| d.parent() != Some(top.as_path()) && *d != top) {
+        if fs::remove_dir(d).is_err() {
+            break;
+        }
+        dir = d.parent();
+    }
+}
+
+/// Find the ref `name` refers to and read it, `Ok(None)` if there is no such ref.
+fn resolve_ref(name: &str) -> anyhow::Result<Option<String>> {
+    find_ref(name)?.map(|full| read_ref(&full)).transpose()
+}
+
+/// The full name (like `refs/heads/master`) of the ref the short `name` refers to.
+fn find_ref(name: &str) -> anyhow::Result<Option<String>> {
+    if name.is_empty() || name.split('/').any(|part| part.is_empty() || part == "..") {
+        return Ok(None);
+    }
+    let packed = packed_refs()?;
+    Ok(REF_RULES
+        .iter()
+        // Only full ref names and all caps names like `HEAD` are taken as is, so a
+        // branch can't be confused with the repo's own `config` or `objects`
+        .filter(|rule| {
+            **rule != "{}"
+                || name.starts_with("refs/")
+                || name.bytes().all(|ch| ch.is_ascii_uppercase() || ch == b'_')
+        })
+        .map(|rule| rule.replace("{}", name))
+        .find(|full| {
+            repo::dir().join(full).is_file() || packed.iter().any(|(name, _)| name == full)
+        }))
+}
+
+/// The short name of the ref `name` ends up at after following symbolic refs, like
+/// `master` for `HEAD` on that branch, `None` when `name` isn't a ref.
+///
+/// A detached `HEAD` is its own ref and stays `HEAD`.
+pub fn abbrev_ref(name: &str) -> anyhow::Result<Option<String>> {
+    let Some(full) = find_ref(name)? else {
+        return Ok(None);
+    };
+    let full = deref_ref(&full)?;
+    let short = ["refs/heads/", "refs/tags/", "refs/remotes/", "refs/"]
+        .iter()
+        .find_map(|prefix| full.strip_prefix(prefix))
+        .unwrap_or(&full);
+    Ok(Some(short.to_string()))
+}
+
+/// How many `ref: ` symbolic refs are followed before giving up, like git.
+const MAX_SYMREF_DEPTH: usize = 5;
+
+/// Read the ref `name` (relative to the repo directory), following `ref: ` symbolic refs.
+///
+/// A loose ref file wins over the same ref in `packed-refs`.
+pub fn read_ref(name: &str) -> anyhow::Result<String> {
+    read_ref_at_depth(name, 0)
+}
+
+fn read_ref_at_depth(name: &str, depth: usize) -> anyhow::Result<String> {
+    let path = repo::dir().join(name);
+    if !path.is_file() {
+        if let Some((_, sha)) = packed_refs()?
+            .into_iter()
+            .find(|(packed, _)| packed == name)
+        {
+            return Ok(sha);
+        }
+    }
+    let content = fs::read_to_string(path).with_context(|| format!("reading ref '{}'", name))?;
+    match content.trim().strip_prefix("ref: ") {
+        Some(target) => {
+            check_ref_name(target).with_context(|| format!("{} is a bad symbolic ref", name))?;
+            anyhow::ensure!(
+                depth < MAX_SYMREF_DEPTH,
+                "{} is more than {} symbolic refs deep, is there a loop?",
+                name,
+                MAX_SYMREF_DEPTH
+            );
+            let exists = repo::dir().join(target).is_file()
+                || packed_refs()?.iter().any(|(packed, _)| packed == target);
+            anyhow::ensure!(
+                exists,
+                "{} points at '{}' which has no commits yet",
+                name,
+                target
+            );
+            read_ref_at_depth(target, depth + 1)
+        }
+        None => Ok(content.trim().to_string()),
+    }
+}
+
+/// The ref the full ref name `name` stands for once symbolic refs are followed, like
+/// `refs/heads/master` for `HEAD` on that branch.
+///
+/// A symbolic ref can point at a branch with no commits yet, it is still named, and
+/// packed refs are never symbolic.
+fn deref_ref(name: &str) -> anyhow::Result<String> {
+    let mut full = name.to_string();
+    let mut depth = 0;
+    while let Ok(content) = fs::read_to_string(repo::dir().join(&full)) {
+        let Some(target) = content.trim().strip_prefix("ref: ") else {
+            break;
+        };
+        check_ref_name(target).with_context(|| format!("{} is a bad symbolic ref", full))?;
+        anyhow::ensure!(
+            depth < MAX_SYMREF_DEPTH,
+            "{} is more than {} symbolic refs deep, is there a loop?",
+            name,
+            MAX_SYMREF_DEPTH
+        );
+        depth += 1;
+        full = target.to_string();
+    }
+    Ok(full)
+}
+
+/// Fail unless `name` is `HEAD` or a full ref name under `refs/` that stays inside the
+/// repository.
+fn check_ref_name(name: &str) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        (name == "HEAD" || name.starts_with("refs/"))
+            && !name.ends_with(".lock")
+            && !name.split('/').any(|part| part.is_empty() || part == ".."),
+        "'{}' is not a valid ref name",
+        name
+    );
+    Ok(())
+}
+
+/// The ref `update_ref` would write for `name` and the SHA it holds now, `None` when it
+/// doesn't exist yet.
+pub fn ref_target(name: &str) -> anyhow::Result<(String, Option<String>)> {
+    check_ref_name(name)?;
+    let full = deref_ref(name)?;
+    let old = read_ref(&full).ok();
+    Ok((full, old))
+}
+
+/// Point the ref `name` (`HEAD` or a full name like `refs/heads/master`) at `sha`, through
+/// the ref it is a symbolic ref to, returns the ref that was written.
+///
+/// With `old` the ref is only written if it still points at that SHA, all zeros for a
+/// ref that must not exist yet, so two updates racing can't lose one.
+pub fn update_ref(name: &str, sha: &str, old: Option<&str>) -> anyhow::Result<String> {
+    let (full, _) = ref_target(name)?;
+    let path = repo::dir().join(&full);
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    // Written aside and renamed so a reader never sees half a SHA, only one update can
+    // hold the lock file
+    let lock = repo::dir().join(format!("{}.lock", full));
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&lock)
+        .with_context(|| format!("can't lock ref '{}', is another update running?", full))?;
+    if let Some(old) = old {
+        if let Err(e) = check_old_value(&full, old) {
+            drop(file);
+            fs::remove_file(&lock)?;
+            return Err(e);
+        }
+    }
+    file.write_all(format!("{}\n", sha).as_bytes())
+        .with_context(|| format!("writing ref '{}'", full))?;
+    fs::rename(&lock, &path).with_context(|| format!("writing ref '{}'", full))?;
+    Ok(full)
+}
+
+/// Delete the ref `name` (`HEAD` or a full name), through the ref it is a symbolic ref
+/// to, removing its loose file and its line in `packed-refs`, returns the ref deleted.
+///
+/// With `old` the ref is only deleted if it still points at that SHA.
+pub fn delete_ref(name: &str, old: Option<&str>) -> anyhow::Result<String> {
+    let (full, current) = ref_target(name)?;
+    anyhow::ensure!(current.is_some(), "ref '{}' doesn't exist", full);
+    let lock = repo::dir().join(format!("{}.lock", full));
+    fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&lock)
+        .with_context(|| format!("can't lock ref '{}', is another update running?", full))?;
+    let deleted = remove_ref(&full, old);
+    fs::remove_file(&lock)?;
+    deleted?;
+    prune_ref_dirs(&repo::dir().join(&full));
+    Ok(full)
+}
+
+/// The part of `delete_ref` done while holding the ref's lock.
+fn remove_ref(full: &str, old: Option<&str>) -> anyhow::Result<()> {
+    if let Some(old) = old {
+        check_old_value(full, old)?;
+    }
+    // The packed line goes first, or the packed SHA would show through once the loose
+    // file is gone
+    let packed = repo::dir().join("packed-refs");
+    if let Ok(content) = fs::read_to_string(&packed) {
+        let mut kept = String::new();
+        let mut dropped = false;
+        for line in content.lines() {
+            // A `^<sha>` line belongs to the ref on the line before it
+            if !line.starts_with('^') {
+                dropped = line.split_once(' ').is_some_and(|(_, name)| name == full);
+            }
+            if !dropped {
+                kept.push_str(line);
+                kept.push('\n');
+            }
+        }
+        if kept.len() != content.len() {
+            let lock = repo::dir().join("packed-refs.lock");
+            fs::write(&lock, kept).context("writing packed-refs")?;
+            fs::rename(&lock, &packed).context("writing packed-refs")?;
+        }
+    }
+    let path = repo::dir().join(full);
+    if path.is_file() {
+        fs::remove_file(&path).with_context(|| format!("deleting ref '{}'", full))?;
+    }
+    Ok(())
+}
+
+/// Fail unless the full ref name `full` points at `old`, all zeros for no ref at all.
+pub fn check_old_value(full: &str, old: &str) -> anyhow::Result<()> {
+    let current = read_ref(full).ok();
+    let matches = match &current {
+        Some(current) => current == old,
+        None => old.bytes().all(|ch| ch == b'0'),
+    };
+    anyhow::ensure!(
+        matches,
+        "ref '{}' changed, it is at {} and not {}",
+        full,
+        current.as_deref().unwrap_or("(none)"),
+        old
+    );
+    Ok(())
+}
+
+/// Point HEAD at the commit `sha`, through the branch it is on or directly when detached.
+pub fn set_head(sha: &str) -> anyhow::Result<()> {
+    update_ref("HEAD", sha, None).map(|_| ())
+}
+
+/// The shortest prefix of `sha`, at least `min_len` long, that no other object shares.
+pub fn abbrev(sha: &str, min_len: usize) -> String {
+    (min_len..sha.len())
+        .map(|len| &sha[..len])
+        .find(|prefix| abbrev_matches(prefix).is_ok_and(|found| found.iter().all(|f| f == sha)))
+        .unwrap_or(sha)
+        .to_string()
+}
+
+/// Expand the lowercase hex `prefix` to the one object SHA starting with it.
+fn resolve_abbrev(prefix: &str) -> anyhow::Result<String> {
+    match abbrev_matches(prefix)?.as_slice() {
+        [sha] => Ok(sha.clone()),
+        [] => anyhow::bail!("unknown revision '{}'", prefix),
+        _ => anyhow::bail!("short SHA '{}' is ambiguous", prefix),
+    }
+}
+
+/// Every object SHA in the store starting with the lowercase hex `prefix`.
+fn abbrev_matches(prefix: &str) -> anyhow::Result<Vec<String>> {
+    let (dir, rest) = prefix.split_at(FANOUT_LEN);
+    let mut found = vec![];
+    if let Ok(entries) = fs::read_dir(repo::objects().join(dir)) {
+        for e in entries {
+            let file = e?.file_name().to_string_lossy().to_string();
+            if file.starts_with(rest) {
+                found.push(format!("{}{}", dir, file));
+            }
+        }
+    }
+    if EMPTY_TREE_SHA.starts_with(prefix) && !found.iter().any(|sha| sha == EMPTY_TREE_SHA) {
+        found.push(EMPTY_TREE_SHA.to_string());
+    }
+    Ok(found)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{commit, temp_repo};
+
+    #[test]
+    fn read_ref_stops_at_a_symbolic_ref_loop() {
+        temp_repo("symref-loop");
+        fs::create_dir_all(repo::dir().join("refs/heads")).unwrap();
+        fs::write(repo::dir().join("refs/heads/a"), "ref: refs/heads/b\n").unwrap();
+        fs::write(repo::dir().join("refs/heads/b"), "ref: refs/heads/a\n").unwrap();
+        let err = read_ref("refs/heads/a").unwrap_err();
+        assert!(
+            format!("{:#}", err).contains("is there a loop?"),
+            "{:#}",
+            err
+        );
+    }
+
+    #[test]
+    fn read_ref_refuses_a_symbolic_ref_out_of_the_repository() {
+        let top = temp_repo("symref-escape");
+        fs::create_dir_all(repo::dir().join("refs/heads")).unwrap();
+        fs::write(top.join("x"), "4b825dc642cb6eb9a060e54bf8d69288fbee4904\n").unwrap();
+        fs::write(repo::dir().join("refs/heads/a"), "ref: ../../x\n").unwrap();
+        let err = read_ref("refs/heads/a").unwrap_err();
+        assert!(
+            format!("{:#}", err).contains("not a valid ref name"),
+            "{:#}",
+            err
+        );
+    }
+
+    #[test]
+    fn update_ref_stops_at_a_symbolic_ref_loop() {
+        temp_repo("update-ref-loop");
+        fs::create_dir_all(repo::dir().join("refs/heads")).unwrap();
+        fs::write(repo::dir().join("refs/heads/a"), "ref: refs/heads/b\n").unwrap();
+        fs::write(repo::dir().join("refs/heads/b"), "ref: refs/heads/a\n").unwrap();
+        let err = update_ref("refs/heads/a", EMPTY_TREE_SHA, None).unwrap_err();
+        assert!(
+            format!("{:#}", err).contains("is there a loop?"),
+            "{:#}",
+            err
+        );
+        assert!(abbrev_ref("refs/heads/a").is_err());
+    }
+
+    /// Three commits in a row on top of the empty tree, oldest first.
+    fn history() -> [String; 3] {
+        let first = commit(EMPTY_TREE_SHA, &[], "first");
+        let second = commit(EMPTY_TREE_SHA, &[&first], "second");
+        let third = commit(EMPTY_TREE_SHA, &[&second], "third");
+        [first, second, third]
+    }
+
+    #[test]
+    fn loose_refs_win_over_packed_refs() {
+        temp_repo("packed-refs");
+        let [first, second, third] = history();
+        fs::write(
+            repo::dir().join("packed-refs"),
+            format!(
+                "# pack-refs with: peeled fully-peeled sorted \n\
+                 {first} refs/heads/master\n\
+                 {second} refs/tags/v1\n\
+                 ^{third}\n"
+            ),
+        )
+        .unwrap();
+        assert_eq!(resolve("master").unwrap(), first);
+        assert_eq!(resolve("v1").unwrap(), second);
+        assert_eq!(
+            list_refs("refs").unwrap(),
+            [
+                ("refs/heads/master".to_string(), first),
+                ("refs/tags/v1".to_string(), second.clone())
+            ]
+        );
+
+        update_ref("refs/heads/master", &third, None).unwrap();
+        assert_eq!(resolve("master").unwrap(), third);
+        assert_eq!(
+            list_refs("refs").unwrap(),
+            [
+                ("refs/heads/master".to_string(), third),
+                ("refs/tags/v1".to_string(), second)
+            ]
+        );
+    }
+
+    #[test]
+    fn pack_refs_keeps_branches_resolving_and_head_loose() {
+        temp_repo("pack-refs");
+        let [first, second, _] = history();
+        update_ref("HEAD", &first, None).unwrap();
+        update_ref("refs/heads/topic", &second, None).unwrap();
+
+        assert_eq!(pack_refs().unwrap(), 2);
+        assert!(!repo::dir().join("refs/heads/master").exists());
+        assert!(!repo::dir().join("refs/heads/topic").exists());
+        assert_eq!(resolve("master").unwrap(), first);
+        assert_eq!(resolve("topic").unwrap(), second);
+        assert_eq!(
+            fs::read_to_string(repo::dir().join("HEAD")).unwrap(),
+            "ref: refs/heads/master\n"
+        );
+        assert_eq!(resolve("HEAD").unwrap(), first);
+    }
+}
@@ -0,0 +1,339 @@
+//! The loose object store under `.idiot/objects`.
+use std::{
+    fs,
+    io::{self, BufRead, BufReader, Read},
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use flate2::bufread::ZlibDecoder;
+use sha1::{Digest, Sha1};
+
+use crate::{
+    compress_from, compress_obj, decomp_obj, is_zlib, log, parse_header_as, repo,
+    tree::EMPTY_TREE_SHA, HashReader,
+};
+
+/// How many hex characters of a SHA name the directory its object goes in.
+pub const FANOUT_LEN: usize = 2;
+
+/// Where the loose object `sha` lives, `.idiot/objects/<fanout>/<rest>`.
+///
+/// Uppercase hex is accepted and maps to the same lowercase file.
+pub fn object_path(sha: &str) -> anyhow::Result<PathBuf> {
+    object_path_in(&repo::objects(), sha)
+}
+
+/// Like [`object_path`] in the objects directory `objects` of any repository.
+fn object_path_in(objects: &Path, sha: &str) -> anyhow::Result<PathBuf> {
+    anyhow::ensure!(
+        sha.len() == 40 && sha.bytes().all(|ch| ch.is_ascii_hexdigit()),
+        "not a valid object name '{}'",
+        sha
+    );
+    let sha = sha.to_ascii_lowercase();
+    let (dir, file) = sha.split_at(FANOUT_LEN);
+    Ok(objects.join(dir).join(file))
+}
+
+/// Every loose object in the objects directory `root` as `(sha, compressed bytes)`, in SHA
+/// order, each one is only read once the iterator gets to it.
+///
+/// `pack/`, `info/` and anything else that isn't a fanout directory is skipped.
+pub fn iter_loose_objects(root: &Path) -> impl Iterator<Item = anyhow::Result<(String, Vec<u8>)>> {
+    let (found, listing_error) = match loose_object_paths(root) {
+        Ok(found) => (found, None),
+        Err(e) => (vec![], Some(e)),
+    };
+    listing_error
+        .map(Err)
+        .into_iter()
+        .chain(found.into_iter().map(|(sha, path)| {
+            let bytes = fs::read(&path)
+                .with_context(|| format!("no git object at '{}'", path.display()))?;
+            Ok((sha, bytes))
+        }))
+}
+
+fn loose_object_paths(root: &Path) -> anyhow::Result<Vec<(String, PathBuf)>> {
+    let mut found = vec![];
+    for dir in fs::read_dir(root).context("reading the object store")? {
+        let dir = dir?;
+        let fanout = dir.file_name().to_string_lossy().to_string();
+        if fanout.len() != FANOUT_LEN || !fanout.bytes().all(|ch| ch.is_ascii_hexdigit()) {
+            continue;
+        }
+        for file in fs::read_dir(dir.path())? {
+            let file = file?;
+            let sha = format!("{}{}", fanout, file.file_name().to_string_lossy());
+            if sha.len() == 40 && sha.bytes().all(|ch| ch.is_ascii_hexdigit()) {
+                found.push((sha, file.path()));
+            }
+        }
+    }
+    found.sort();
+    Ok(found)
+}
+
+/// Read and decompress the object `sha` from the store.
+///
+/// Like git the empty tree is always readable even when it was never written.
+pub fn read_object(sha: &str) -> anyhow::Result<Vec<u8>> {
+    read_object_in(&repo::objects(), sha)
+}
+
+/// Like [`read_object`] from the objects directory `objects` of another repository, like a
+/// submodule's.
+pub fn read_object_in(objects: &Path, sha: &str) -> anyhow::Result<Vec<u8>> {
+    let path = object_path_in(objects, sha)?;
+    let bytes = match fs::read(&path) {
+        Ok(bytes) => bytes,
+        Err(e)
+            if e.kind() == io::ErrorKind::NotFound && sha.eq_ignore_ascii_case(EMPTY_TREE_SHA) =>
+        {
+            return Ok(b"tree 0\0".to_vec());
+        }
+        Err(e) => return Err(e).with_context(|| format!("no git object at '{}'", path.display())),
+    };
+    decomp_obj(&bytes).context("decompressing object")
+}
+
+/// The content of the blob `sha`, its header stripped, an error for any other object.
+pub fn read_blob(sha: &str) -> anyhow::Result<Vec<u8>> {
+    let (kind, size, mut reader) = open_object(sha)?;
+    anyhow::ensure!(kind == "blob", "object {} is a {}, not a blob", sha, kind);
+    let mut content = Vec::with_capacity(size);
+    reader
+        .read_to_end(&mut content)
+        .context("uncompressing object")?;
+    Ok(content)
+}
+
+/// Open the object `sha` for streaming, returns its type, its size and a reader over the
+/// content that follows the header.
+///
+/// Only the start of the object is inflated until the reader is used, so asking for the
+/// type or size (`cat-file -s`, `--batch-check`) costs the same for any size of object and
+/// needs no cache.
+pub fn open_object(sha: &str) -> anyhow::Result<(String, usize, Box<dyn BufRead>)> {
+    open_object_as(sha, false)
+}
+
+/// Like [`open_object`], with `allow_unknown_type` any type in the header is accepted, for
+/// looking into damaged objects.
+pub fn open_object_as(
+    sha: &str,
+    allow_unknown_type: bool,
+) -> anyhow::Result<(String, usize, Box<dyn BufRead>)> {
+    let path = object_path(sha)?;
+    let obj_file = match fs::File::open(&path) {
+        Ok(obj_file) => obj_file,
+        Err(e)
+            if e.kind() == io::ErrorKind::NotFound && sha.eq_ignore_ascii_case(EMPTY_TREE_SHA) =>
+        {
+            return Ok(("tree".to_string(), 0, Box::new(io::empty())));
+        }
+        Err(e) => return Err(e).with_context(|| format!("no git object at '{}'", path.display())),
+    };
+    open_loose(BufReader::new(obj_file), sha, allow_unknown_type)
+}
+
+/// Read the header of the loose object `sha` from its stored bytes `input`, returns its
+/// type, its size and a reader over the content that follows.
+pub fn open_loose<'a, R: BufRead + 'a>(
+    mut input: R,
+    sha: &str,
+    allow_unknown_type: bool,
+) -> anyhow::Result<(String, usize, Box<dyn BufRead + 'a>)> {
+    let mut reader: Box<dyn BufRead + 'a> = if is_zlib(input.fill_buf()?) {
+        Box::new(BufReader::new(ZlibDecoder::new(input)))
+    } else {
+        Box::new(input)
+    };
+    let mut header = vec![];
+    reader
+        .read_until(b'\0', &mut header)
+        .context("uncompressing object")?;
+    let (kind, size) = parse_header_as(&header, allow_unknown_type)
+        .with_context(|| format!("object {} has a bad header", sha))?;
+    Ok((kind, size, reader))
+}
+
+/// Hash `content` (a full object including its header), write it to the store and
+/// return the hex SHA.
+pub fn write_object(content: &[u8], store: bool) -> anyhow::Result<String> {
+    let sha_hash = hex::encode(Sha1::digest(content));
+    let path = object_path(&sha_hash)?;
+    create_fanout_dir(&path)?;
+    let encoded = compress_obj(content, store).context("compressing object")?;
+    fs::write(&path, encoded).with_context(|| format!("failed to write to {}", path.display()))?;
+    log::debug!("wrote object {}", sha_hash);
+    Ok(sha_hash)
+}
+
+/// Hash the blob of the `len` bytes read from `content` returning the hex SHA, when `write`
+/// is set the object is streamed into the store too (uncompressed if `store` is set).
+///
+/// Every blob is hashed through here, the content is never held in memory whole.
+pub fn write_blob<R: Read>(
+    content: R,
+    len: u64,
+    write: bool,
+    store: bool,
+) -> anyhow::Result<String> {
+    let mut input = blob_reader(content, len);
+    if !write {
+        io::copy(&mut input, &mut io::sink())?;
+        return Ok(hex::encode(input.hasher.finalize()));
+    }
+
+    // Compress into a temp file since the final location depends on the hash
+    let tmp_path = repo::objects().join(format!("tmp_obj_{}", std::process::id()));
+    let mut out = fs::File::create(&tmp_path)
+        .with_context(|| format!("failed to write to {}", tmp_path.display()))?;
+    compress_from(&mut input, &mut out, store).context("compressing object")?;
+    drop(out);
+
+    let sha_hash = hex::encode(input.hasher.finalize());
+    let path = object_path(&sha_hash)?;
+    create_fanout_dir(&path)?;
+    fs::rename(&tmp_path, &path)
+        .with_context(|| format!("failed to write to {}", path.display()))?;
+    log::debug!("wrote blob {}", sha_hash);
+    Ok(sha_hash)
+}
+
+/// The loose blob object for the `len` bytes of `content`, its `blob <len>\0` header then
+/// the content, hashed as it is read.
+pub fn blob_reader<R: Read>(content: R, len: u64) -> HashReader<impl Read> {
+    HashReader {
+        inner: io::Cursor::new(format!("blob {}\0", len)).chain(content),
+        hasher: Sha1::new(),
+    }
+}
+
+/// Make sure the fanout directory an object at `path` goes in exists.
+pub fn create_fanout_dir(path: &Path) -> anyhow::Result<()> {
+    let dir = path.parent().expect("object paths have a fanout directory");
+    match fs::create_dir(dir) {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => Ok(()),
+        Err(e) => Err(e).with_context(|| format!("failed to create {}", dir.display())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        testutil::{blob, commit, temp_repo, tree},
+        tree::Mode,
+    };
+
+    #[test]
+    fn object_path_splits_off_the_fanout_directory() {
+        let objects = Path::new("/repo/objects");
+        let sha = "4b825dc642cb6eb9a060e54bf8d69288fbee4904";
+        let expected = objects
+            .join("4b")
+            .join("825dc642cb6eb9a060e54bf8d69288fbee4904");
+        assert_eq!(object_path_in(objects, sha).unwrap(), expected);
+        assert_eq!(
+            object_path_in(objects, &sha.to_ascii_uppercase()).unwrap(),
+            expected
+        );
+        for bad in ["", "4b825d", &format!("{}00", sha), &sha.replace('b', "g")] {
+            assert!(object_path_in(objects, bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn write_blob_hashes_like_git() {
+        temp_repo("write-blob");
+        // From `git hash-object`
+        let samples: [(&[u8], &str); 3] = [
+            (b"", "e69de29bb2d1d6434b8b29ae775ad8c2e48c5391"),
+            (b"hello world\n", "3b18e512dba79e4c8300dd08aeb37f8e728b8dad"),
+            (b"a\0b\xff", "f63bd877fcd57b07f0339277c3de5bf7bd442cac"),
+        ];
+        for (content, sha) in samples {
+            let len = content.len() as u64;
+            assert_eq!(write_blob(content, len, false, false).unwrap(), sha);
+            assert!(!object_path(sha).unwrap().exists());
+            assert_eq!(write_blob(content, len, true, false).unwrap(), sha);
+            let obj = read_object(sha).unwrap();
+            assert_eq!(
+                obj,
+                [format!("blob {}\0", len).as_bytes(), content].concat()
+            );
+        }
+    }
+
+    #[test]
+    fn stored_objects_are_plain_and_read_back() {
+        temp_repo("stored-object");
+        let obj = b"blob 11\0hello world";
+        let sha = write_object(obj, true).unwrap();
+        assert_eq!(sha, "95d09f2b10159347eece71399a7e2e907ea3df4f");
+        assert_eq!(fs::read(object_path(&sha).unwrap()).unwrap(), obj);
+        assert_eq!(read_object(&sha).unwrap(), obj);
+        assert_eq!(read_blob(&sha).unwrap(), b"hello world");
+    }
+
+    #[test]
+    fn open_object_reads_the_size_without_inflating_the_content() {
+        temp_repo("open-object-size");
+        let sha = "0123456789abcdef0123456789abcdef01234567";
+        let size = 1 << 20;
+        let obj = [format!("blob {}\0", size).into_bytes(), vec![b'x'; size]].concat();
+        let compressed = compress_obj(&obj, false).unwrap();
+        // Cut off most of the compressed content, only the header has to be readable
+        let path = object_path(sha).unwrap();
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, &compressed[..compressed.len() / 4]).unwrap();
+
+        let (kind, len, _) = open_object(sha).unwrap();
+        assert_eq!((kind.as_str(), len), ("blob", size));
+        assert!(read_object(sha).is_err());
+    }
+
+    #[test]
+    fn read_blob_reads_only_blobs() {
+        temp_repo("read-blob");
+        let sha = blob("hello world\n");
+        assert_eq!(read_blob(&sha).unwrap(), b"hello world\n");
+
+        let tree = tree(&[(Mode::FileBlob, "hello", &sha)]);
+        let commit = commit(&tree, &[], "hello");
+        for (sha, kind) in [(tree, "tree"), (commit, "commit")] {
+            let err = read_blob(&sha).unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                format!("object {} is a {}, not a blob", sha, kind)
+            );
+        }
+    }
+
+    #[test]
+    fn iter_loose_objects_lists_the_store_in_sha_order() {
+        temp_repo("iter-loose-objects");
+        let mut shas = [blob("one"), blob("two"), blob("three")];
+        shas.sort();
+        let objects = repo::objects();
+        fs::create_dir_all(objects.join("pack")).unwrap();
+        fs::write(objects.join("pack/pack-0.pack"), "not an object").unwrap();
+        fs::create_dir_all(objects.join("info")).unwrap();
+        fs::write(objects.join("info/packs"), "").unwrap();
+
+        let listed = iter_loose_objects(&objects)
+            .map(|object| object.unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            listed.iter().map(|(sha, _)| sha).collect::<Vec<_>>(),
+            shas.iter().collect::<Vec<_>>()
+        );
+        for (sha, bytes) in listed {
+            assert_eq!(bytes, fs::read(object_path(&sha).unwrap()).unwrap());
+        }
+    }
+}
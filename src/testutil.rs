@@ -0,0 +1,81 @@
+//! Scratch repositories and objects for tests.
+use std::{
+    fs,
+    path::PathBuf,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use crate::{
+    init_repo, repo,
+    store::write_object,
+    tree::{GitObject, Mode, ObjType},
+    IDIOT,
+};
+
+/// A new empty directory under the system temp directory for the test `name`.
+pub fn temp_dir(name: &str) -> PathBuf {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    let dir = std::env::temp_dir().join(format!(
+        "idiot-test-{}-{}-{}",
+        name,
+        std::process::id(),
+        NEXT.fetch_add(1, Ordering::SeqCst)
+    ));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// A new repository with a work tree for the test `name`, opened for the calling thread,
+/// returns the top of the work tree.
+pub fn temp_repo(name: &str) -> PathBuf {
+    let top = temp_dir(name);
+    init_repo(&top.join(IDIOT)).unwrap();
+    repo::open(&top.join(IDIOT));
+    top
+}
+
+/// Store the blob `content`, returns its SHA.
+pub fn blob(content: &str) -> String {
+    let mut obj = format!("blob {}\0", content.len()).into_bytes();
+    obj.extend(content.as_bytes());
+    write_object(&obj, false).unwrap()
+}
+
+/// Store a tree of `(mode, name, sha)` entries, returns its SHA.
+pub fn tree(entries: &[(Mode, &str, &str)]) -> String {
+    let mut objs = entries
+        .iter()
+        .map(|(mode, name, sha)| GitObject {
+            mode: *mode,
+            obj_type: ObjType::Blob {
+                path: name.to_string(),
+                content: vec![],
+            },
+            sha: Some(hex::decode(sha).unwrap()),
+        })
+        .collect::<Vec<_>>();
+    objs.sort();
+    let bytes = objs
+        .iter()
+        .map(|o| o.tree_content_bytes().unwrap())
+        .collect::<Vec<_>>()
+        .concat();
+    let mut obj = format!("tree {}\0", bytes.len()).into_bytes();
+    obj.extend(bytes);
+    write_object(&obj, false).unwrap()
+}
+
+/// Store a commit of `tree` with `parents`, returns its SHA.
+pub fn commit(tree: &str, parents: &[&str], message: &str) -> String {
+    let mut text = format!("tree {}\n", tree);
+    for parent in parents {
+        text.push_str(&format!("parent {}\n", parent));
+    }
+    text.push_str("author A U Thor <author@example.com> 1112911993 -0700\n");
+    text.push_str("committer C O Mitter <committer@example.com> 1112911993 -0700\n");
+    text.push_str(&format!("\n{}\n", message));
+    let mut obj = format!("commit {}\0", text.len()).into_bytes();
+    obj.extend(text.as_bytes());
+    write_object(&obj, false).unwrap()
+}
@@ -1,45 +1,239 @@
 use std::{
     cmp,
-    collections::BTreeSet,
     fmt::{self, Write},
     fs,
-    io::Read,
-    path::Path,
-    sync::LazyLock,
+    path::{Component, Path, PathBuf},
 };
 
 use anyhow::Context;
-use flate2::{bufread::ZlibEncoder, Compression};
-use sha1::{Digest, Sha1};
 
-static IGNORE: LazyLock<BTreeSet<String>> = LazyLock::new(|| {
-    let mut ignore = BTreeSet::new();
+use crate::{color::paint, config::Config, hash_file, log, progress::Progress, rev, store, IDIOT};
 
-    ignore.insert(".git/".to_string());
-    ignore.insert(".idiot/".to_string());
+/// The ignore rules for one walk of a working tree.
+///
+/// Built from the `.gitignore` of the tree being walked rather than whatever directory
+/// the process happens to start in.
+#[derive(Debug)]
+pub struct Ignore {
+    rules: Vec<IgnoreRule>,
+    /// Match without regard to case, like git with `core.ignoreCase`.
+    ignore_case: bool,
+}
 
-    if let Ok(s) = fs::read_to_string("./.gitignore") {
-        for f in s.lines() {
-            let pat = f.trim();
-            if pat.is_empty() || pat.starts_with('#') {
-                continue;
+/// One pattern of an [`Ignore`] and where it came from.
+#[derive(Debug)]
+pub struct IgnoreRule {
+    pub pattern: String,
+    /// The file the pattern is from, `None` for the repository directories always ignored.
+    pub source: Option<String>,
+    /// The pattern's line in `source`, from `1`.
+    pub line: usize,
+}
+
+impl IgnoreRule {
+    /// A `!pattern` that re-includes what an earlier pattern ignored.
+    pub fn is_negated(&self) -> bool {
+        self.pattern.starts_with('!')
+    }
+
+    /// Match the `/` separated `path` from the top of the work tree, like git a pattern
+    /// with a `/` before its end is anchored to the top, otherwise it matches a name at
+    /// any depth, and a trailing `/` only matches directories.
+    ///
+    /// With `ignore_case` the pattern is lowercased, `path` must already be.
+    fn matches(&self, path: &str, is_dir: bool, ignore_case: bool) -> bool {
+        let lower;
+        let pattern = if ignore_case {
+            lower = self.pattern.to_lowercase();
+            &lower
+        } else {
+            &self.pattern
+        };
+        // A leading `\` escapes a `!` or `#` that is part of the name
+        let full = pattern.strip_prefix('!').unwrap_or(pattern);
+        let full = full.strip_prefix('\\').unwrap_or(full);
+        let pattern = full.trim_end_matches('/');
+        if pattern.len() != full.len() && !is_dir {
+            return false;
+        }
+        if pattern.contains('/') {
+            glob_match(pattern.trim_start_matches('/').as_bytes(), path.as_bytes())
+        } else {
+            let name = path.rsplit('/').next().unwrap_or(path);
+            glob_match(pattern.as_bytes(), name.as_bytes())
+        }
+    }
+}
+
+impl Ignore {
+    /// Only ignores the repository directories, `.git/` and `.idiot/`, so a snapshot
+    /// never contains itself.
+    pub fn repo_dirs() -> Self {
+        let rules = [".git/", ".idiot/"]
+            .into_iter()
+            .map(|pattern| IgnoreRule {
+                pattern: pattern.to_string(),
+                source: None,
+                line: 0,
+            })
+            .collect();
+        Self {
+            rules,
+            ignore_case: false,
+        }
+    }
+
+    /// Always ignores `.git/` and `.idiot/` plus the patterns in `<root>/.gitignore`,
+    /// ignoring case when `core.ignoreCase` is set.
+    pub fn from_root<P: AsRef<Path>>(root: P) -> Self {
+        let Self { mut rules, .. } = Self::repo_dirs();
+        if let Ok(s) = fs::read_to_string(root.as_ref().join(".gitignore")) {
+            for (idx, f) in s.lines().enumerate() {
+                let pat = f.trim();
+                if pat.is_empty() || pat.starts_with('#') {
+                    continue;
+                }
+                rules.push(IgnoreRule {
+                    pattern: pat.to_string(),
+                    source: Some(".gitignore".to_string()),
+                    line: idx + 1,
+                });
+            }
+        }
+        let ignore_case = Config::read().get("core.ignorecase").is_some_and(|value| {
+            ["true", "yes", "on", "1"].contains(&value.to_lowercase().as_str())
+        });
+        Self { rules, ignore_case }
+    }
+
+    /// Match patterns and paths without regard to case, whatever `core.ignoreCase` says.
+    pub fn ignore_case(mut self, ignore_case: bool) -> Self {
+        self.ignore_case = ignore_case;
+        self
+    }
+
+    pub fn is_ignored(&self, p: &Path) -> bool {
+        self.matching_rule(p).is_some_and(|rule| !rule.is_negated())
+    }
+
+    /// The rule that decides whether `p` (relative to the top of the work tree) is ignored,
+    /// a negated rule when it is re-included.
+    ///
+    /// Like git the last matching pattern wins, and a path inside an ignored directory
+    /// is ignored by that directory's rule, no pattern can re-include it.
+    pub fn matching_rule(&self, p: &Path) -> Option<&IgnoreRule> {
+        let mut path = p.to_string_lossy().to_string();
+        if self.ignore_case {
+            path = path.to_lowercase();
+        }
+        let path = path.trim_start_matches("./");
+        let is_dir = path.ends_with('/') || p.is_dir();
+        let path = path.trim_end_matches('/');
+
+        // Every parent directory first, then the path itself
+        let dirs = path.match_indices('/').map(|(at, _)| (&path[..at], true));
+        let mut last = None;
+        for (sub, sub_is_dir) in dirs.chain([(path, is_dir)]) {
+            last = self
+                .rules
+                .iter()
+                .rev()
+                .find(|r| r.matches(sub, sub_is_dir, self.ignore_case));
+            if last.is_some_and(|rule| !rule.is_negated()) {
+                break;
             }
-            ignore.insert(pat.to_string());
         }
+        last
+    }
+}
+
+/// Match `name` against a glob `pattern` where `*` is any run of characters but `/`, `**`
+/// any run at all and `?` one character.
+pub fn glob_match(pattern: &[u8], name: &[u8]) -> bool {
+    match (pattern.split_first(), name.split_first()) {
+        (None, None) => true,
+        (Some((b'*', rest)), _) if rest.first() == Some(&b'*') => {
+            let rest = &rest[1..];
+            // `**/` can also match no directories at all
+            rest.strip_prefix(b"/")
+                .is_some_and(|after| glob_match(after, name))
+                || glob_match(rest, name)
+                || (!name.is_empty() && glob_match(pattern, &name[1..]))
+        }
+        (Some((b'*', rest)), _) => {
+            glob_match(rest, name)
+                || (!name.is_empty() && name[0] != b'/' && glob_match(pattern, &name[1..]))
+        }
+        (Some((b'?', rest)), Some((n, name_rest))) if *n != b'/' => glob_match(rest, name_rest),
+        (Some((ch, rest)), Some((n, name_rest))) if ch == n => glob_match(rest, name_rest),
+        _ => false,
     }
-    ignore
-});
-fn path_in_ignore(p: &Path) -> bool {
-    for s in IGNORE.iter() {
-        if p.ends_with(s) {
-            return true;
+}
+
+/// Turn a path the user gave, relative to the current directory (which commands expect to
+/// be the top of the work tree) or absolute, into a clean `/` separated path from the top.
+///
+/// `.` and `..` are resolved without touching the file system, a path that ends up
+/// outside the work tree is an error.
+pub fn normalize_repo_path(path: &str) -> anyhow::Result<String> {
+    let root = std::env::current_dir()?;
+    let mut clean = PathBuf::new();
+    for comp in root.join(path).components() {
+        match comp {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                clean.pop();
+            }
+            comp => clean.push(comp),
         }
     }
-    false
+    // An absolute path may reach the work tree through a symlink, which the current dir
+    // never has, so an existing path gets a second chance resolved
+    let resolved = clean.canonicalize().ok();
+    let rel = clean
+        .strip_prefix(&root)
+        .ok()
+        .or_else(|| {
+            resolved
+                .as_ref()?
+                .strip_prefix(root.canonicalize().ok()?)
+                .ok()
+        })
+        .with_context(|| format!("'{}' is outside the repository", path))?;
+    Ok(rel
+        .components()
+        .map(|comp| comp.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/"))
+}
+
+/// Fail unless `name` is safe as one tree entry name to write into a work tree: not empty,
+/// `.` or `..`, not a repository directory (`.git`, `.idiot`) and without `/` or NUL.
+pub fn check_entry_name(name: &str) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        !matches!(name, "" | "." | "..")
+            && !name.eq_ignore_ascii_case(".git")
+            && !name.eq_ignore_ascii_case(IDIOT)
+            && !name.contains(['/', '\0']),
+        "invalid tree entry name '{}'",
+        name.escape_debug()
+    );
+    Ok(())
+}
+
+/// Fail unless every part of the `/` separated `path` from the top of a tree passes
+/// [`check_entry_name`], so checking it out can't write outside the work tree.
+pub fn check_entry_path(path: &str) -> anyhow::Result<()> {
+    path.split('/')
+        .try_for_each(check_entry_name)
+        .with_context(|| format!("refusing to check out '{}'", path.escape_debug()))
 }
 
 const SHA_SIZE: usize = 20;
 
+/// The SHA1 of the tree with no entries (`tree 0\0`), git treats it as always present.
+pub const EMPTY_TREE_SHA: &str = "4b825dc642cb6eb9a060e54bf8d69288fbee4904";
+
 /// The mode of a git tree object.
 #[derive(Clone, Copy, Debug)]
 #[repr(u32)]
@@ -58,6 +252,7 @@ pub enum ObjType {
         /// The file referenced in the tree.
         path: String,
         /// The content the file has.
+        #[allow(dead_code)]
         content: Vec<u8>,
     },
     Tree {
@@ -71,7 +266,19 @@ pub enum ObjType {
         objs: Vec<GitObject>,
     },
     #[allow(dead_code)]
-    Commit,
+    Commit {
+        /// Hex SHA of the tree this commit snapshots.
+        tree: String,
+        /// Hex SHAs of the parent commits, a root commit has none.
+        parents: Vec<String>,
+        /// Every other header line (`author`, `committer`, ...) in the order they appear,
+        /// a header continued over several lines has them joined with `\n`.
+        headers: Vec<(String, String)>,
+        /// The `gpgsig` header, the armored signature over the rest of the commit.
+        signature: Option<String>,
+        /// Everything after the blank line that ends the headers.
+        message: String,
+    },
 }
 
 /// This is an object in a git tree.
@@ -83,29 +290,82 @@ pub struct GitObject {
     pub obj_type: ObjType,
     /// The SHA1 checksum ID of the object in the tree. This is the non hex encoded string.
     ///
-    /// A `None` means the file is to be deleted.
+    /// A `None` on a tree entry is a tombstone, the file is to be deleted. Tombstones only
+    /// belong in changes that haven't been applied yet (staging, merging), a written tree
+    /// can't have one and [`GitObject::tree_content_bytes`] refuses them. A top level object
+    /// parsed with [`GitObject::try_from_bytes`] also has `None`, its SHA is what it was read by.
     pub sha: Option<Vec<u8>>,
 }
 
 impl Mode {
-    pub fn new(kind: usize) -> Self {
-        match kind {
+    /// The mode written as `kind` in a tree, `None` for a mode git doesn't know.
+    pub fn try_new(kind: usize) -> Option<Self> {
+        Some(match kind {
             100644 => Self::FileBlob,
             100755 => Self::ExeBlob,
             40000 => Self::SubDir,
             160000 => Self::SubMod,
             120000 => Self::SymLink,
-            _ => panic!("not a valid mode {}", kind),
+            _ => return None,
+        })
+    }
+
+    /// The type of object an entry with this mode points at.
+    pub fn obj_name(self) -> &'static str {
+        match self {
+            Mode::FileBlob | Mode::ExeBlob | Mode::SymLink => "blob",
+            Mode::SubDir => "tree",
+            Mode::SubMod => "commit",
         }
     }
 }
 
+impl ObjType {
+    /// Parse the body of a commit object, the `commit <size>\0` header already stripped.
+    fn commit_from_bytes(body: &[u8]) -> anyhow::Result<Self> {
+        let text = String::from_utf8_lossy(body);
+        let (head, message) = text.split_once("\n\n").unwrap_or((&text, ""));
+
+        let mut tree = None;
+        let mut parents = vec![];
+        let mut headers: Vec<(String, String)> = vec![];
+        for line in head.lines() {
+            // A line starting with a space continues the header before it, like the lines
+            // of a `gpgsig` block
+            if let Some(more) = line.strip_prefix(' ') {
+                if let Some((_, val)) = headers.last_mut() {
+                    val.push('\n');
+                    val.push_str(more);
+                }
+                continue;
+            }
+            let (key, val) = line.split_once(' ').unwrap_or((line, ""));
+            match key {
+                "tree" => tree = Some(val.to_string()),
+                "parent" => parents.push(val.to_string()),
+                _ => headers.push((key.to_string(), val.to_string())),
+            }
+        }
+        let signature = headers
+            .iter()
+            .position(|(key, _)| key == "gpgsig")
+            .map(|at| headers.remove(at).1);
+        Ok(ObjType::Commit {
+            tree: tree.context("invalid commit object, no tree")?,
+            parents,
+            headers,
+            signature,
+            message: message.to_string(),
+        })
+    }
+}
+
 impl fmt::Display for ObjType {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             ObjType::Blob { .. } => f.write_str("blob"),
             ObjType::Tree { .. } => f.write_str("tree"),
-            ObjType::Commit => f.write_str("commit"),
+            ObjType::Commit { .. } => f.write_str("commit"),
         }
     }
 }
@@ -125,101 +385,174 @@ impl Eq for GitObject {}
 impl Ord for GitObject {
     fn cmp(&self, other: &Self) -> cmp::Ordering {
         match (&self.obj_type, &other.obj_type) {
+            // git compares the raw names but a sub tree sorts as if its name ended in `/`,
+            // so `foo.txt` comes before the directory `foo`
             (
                 ObjType::Blob { path: a, .. } | ObjType::Tree { path: Some(a), .. },
                 ObjType::Blob { path: b, .. } | ObjType::Tree { path: Some(b), .. },
-            ) => a.cmp(b),
+            ) => {
+                let dir_suffix = |mode| matches!(mode, Mode::SubDir).then_some(b'/');
+                a.bytes()
+                    .chain(dir_suffix(self.mode))
+                    .cmp(b.bytes().chain(dir_suffix(other.mode)))
+            }
 
             (ObjType::Blob { .. }, ObjType::Tree { .. }) => cmp::Ordering::Greater,
-            (ObjType::Blob { .. }, ObjType::Commit) => cmp::Ordering::Greater,
+            (ObjType::Blob { .. }, ObjType::Commit { .. }) => cmp::Ordering::Greater,
 
             (ObjType::Tree { path: None, .. }, ObjType::Tree { .. }) => cmp::Ordering::Less,
             (ObjType::Tree { .. }, ObjType::Tree { path: None, .. }) => cmp::Ordering::Greater,
             (ObjType::Tree { .. }, ObjType::Blob { .. }) => cmp::Ordering::Less,
-            (ObjType::Tree { .. }, ObjType::Commit) => cmp::Ordering::Greater,
+            (ObjType::Tree { .. }, ObjType::Commit { .. }) => cmp::Ordering::Greater,
 
-            (ObjType::Commit, ObjType::Blob { .. }) => cmp::Ordering::Less,
-            (ObjType::Commit, ObjType::Tree { .. }) => cmp::Ordering::Less,
-            (ObjType::Commit, ObjType::Commit) => cmp::Ordering::Equal,
+            (ObjType::Commit { .. }, ObjType::Blob { .. }) => cmp::Ordering::Less,
+            (ObjType::Commit { .. }, ObjType::Tree { .. }) => cmp::Ordering::Less,
+            (ObjType::Commit { .. }, ObjType::Commit { .. }) => cmp::Ordering::Equal,
         }
     }
 }
 
 impl GitObject {
-    pub fn from_bytes(bytes: &[u8]) -> Self {
-        let tree_bytes = bytes.split(|ch| ch == &b'\0').collect::<Vec<&[u8]>>();
-
-        let header = tree_bytes[0]
-            .split(|ch| ch == &b' ')
-            .collect::<Vec<&[u8]>>();
+    /// Parse an object with its `<type> <size>\0` header, or a single tree entry without
+    /// one, an error when the bytes are not a well formed object.
+    pub fn try_from_bytes(bytes: &[u8]) -> anyhow::Result<Self> {
+        let (header, body) = match bytes.iter().position(|ch| ch == &b'\0') {
+            Some(idx) => (&bytes[..idx], &bytes[idx + 1..]),
+            None => (bytes, &[][..]),
+        };
+        let header = header.split(|ch| ch == &b' ').collect::<Vec<&[u8]>>();
         if let [b"tree", size_bytes] = header.as_slice() {
-            let size = usize_from_bytes(size_bytes).unwrap();
-            let mut first = vec![];
-            first.extend_from_slice(tree_bytes[1]);
-            first.push(b'\0');
-            first.extend_from_slice(&tree_bytes[2][..SHA_SIZE]);
-
-            let mut objs = vec![first];
-            objs.extend(tree_bytes[2..].windows(2).map(|slice| {
-                let mut obj = vec![];
-                obj.extend_from_slice(&slice[0][SHA_SIZE..]);
-                obj.push(b'\0');
-                obj.extend_from_slice(&slice[1][..SHA_SIZE]);
-                obj
-            }));
-            GitObject {
+            let size = usize_from_bytes(size_bytes).context("invalid tree object size")?;
+
+            // Walk the entries front to back, the binary SHA may contain any byte
+            // (including `\0` and ` `) so we can't split on separators
+            let mut objs = vec![];
+            let mut rest = body;
+            while !rest.is_empty() {
+                let (obj, tail) = GitObject::entry_from_bytes(rest)?;
+                anyhow::ensure!(
+                    obj.sha.as_ref().is_some_and(|sha| sha.len() == SHA_SIZE),
+                    "invalid tree object, truncated sha for '{}'",
+                    obj.as_path_str()
+                );
+                objs.push(obj);
+                rest = tail;
+            }
+            Ok(GitObject {
                 mode: Mode::SubDir,
                 obj_type: ObjType::Tree {
                     // Top level will not have name
                     path: None,
                     size,
-                    objs: objs.iter().map(|b| GitObject::from_bytes(b)).collect(),
+                    objs,
                 },
                 sha: None,
-            }
+            })
+        } else if let [b"commit", _size] = header.as_slice() {
+            Ok(GitObject {
+                // A commit can only be referenced from a tree as a submodule
+                mode: Mode::SubMod,
+                obj_type: ObjType::commit_from_bytes(body)?,
+                sha: None,
+            })
         } else {
-            let mut split = bytes.splitn(2, |ch| ch == &b' ');
-            let mode = match split.next().map(usize_from_bytes) {
-                Some(Ok(m)) => Mode::new(m),
-                _ => panic!("not a number"),
-            };
-            let (path, sha) = match split
-                .next()
-                .map(|b| b.split(|ch| ch == &b'\0').collect::<Vec<&[u8]>>())
-                .as_deref()
-            {
-                Some(&[name, sha]) if !sha.is_empty() => (
-                    String::from_utf8(name.to_vec()).expect("name is utf8"),
-                    Some(sha.to_vec()),
-                ),
-                Some(&[name, sha]) if sha.is_empty() => (
-                    String::from_utf8(name.to_vec()).expect("name is utf8"),
-                    None,
-                ),
-                Some(_) | None => panic!("invalid tree object, no name or sha"),
-            };
-            GitObject {
-                mode,
-                obj_type: ObjType::Blob {
-                    path,
-                    content: "NOT REAL YET".into(),
-                },
-                sha,
-            }
+            Ok(GitObject::entry_from_bytes(bytes)?.0)
         }
     }
 
-    pub fn from_path<P: AsRef<Path>>(path: P) -> anyhow::Result<Self> {
-        let path = path.as_ref();
+    /// Parse a single `[mode] [name]\0[SHA-1 in binary format]` entry returning it and
+    /// the remaining bytes.
+    ///
+    /// An entry with no SHA bytes is a file that is to be deleted.
+    fn entry_from_bytes(bytes: &[u8]) -> anyhow::Result<(Self, &[u8])> {
+        let mut split = bytes.splitn(2, |ch| ch == &b' ');
+        let mode = split.next().unwrap_or_default();
+        let mode = usize_from_bytes(mode)
+            .ok()
+            .and_then(Mode::try_new)
+            .with_context(|| {
+                format!(
+                    "invalid tree object, bad mode '{}'",
+                    String::from_utf8_lossy(mode)
+                )
+            })?;
+        let rest = split.next().unwrap_or_default();
+        let name_end = rest
+            .iter()
+            .position(|ch| ch == &b'\0')
+            .context("invalid tree object, no name or sha")?;
+        let path = String::from_utf8(rest[..name_end].to_vec()).with_context(|| {
+            format!(
+                "invalid tree object, name '{}' is not UTF-8",
+                String::from_utf8_lossy(&rest[..name_end])
+            )
+        })?;
+
+        let rest = &rest[name_end + 1..];
+        let (sha, rest) = rest.split_at(cmp::min(SHA_SIZE, rest.len()));
+        let sha = (!sha.is_empty()).then(|| sha.to_vec());
+        let obj = GitObject {
+            mode,
+            obj_type: ObjType::Blob {
+                path,
+                content: "NOT REAL YET".into(),
+            },
+            sha,
+        };
+        Ok((obj, rest))
+    }
 
+    /// Snapshot the file or directory at `path`, writing every blob and tree to the store
+    /// as the walk reaches it (uncompressed if `store` is set).
+    ///
+    /// Files are streamed into the store and only names and SHAs are kept, so memory
+    /// doesn't grow with the size of the files.
+    ///
+    /// A nested repository is recorded as a gitlink to its HEAD like git does, with
+    /// `recurse_submodules` its files are snapshotted in place instead (without its
+    /// `.git`/`.idiot`), which git never does.
+    pub fn write_path<P: AsRef<Path>>(
+        path: P,
+        ignore: &Ignore,
+        progress: &mut Progress,
+        store: bool,
+        recurse_submodules: bool,
+    ) -> anyhow::Result<Self> {
+        let path = path.as_ref();
         if path.is_dir() {
             let mut objs = vec![];
             for e in fs::read_dir(path)? {
                 let p = e?.path();
-                if path_in_ignore(&p) {
+                // A submodule's `.git` can also be a `gitdir: ` link file
+                let git_link = recurse_submodules && p.file_name() == Some(".git".as_ref());
+                if git_link || ignore.is_ignored(&p) {
+                    log::debug!("skipping ignored '{}'", p.display());
                     continue;
                 }
-                objs.push(GitObject::from_path(p)?)
+                let head = match recurse_submodules {
+                    true => None,
+                    false => submodule_head(&p)?,
+                };
+                match head {
+                    Some(sha) => {
+                        log::info!("recording '{}' as a gitlink", p.display());
+                        objs.push(Self {
+                            mode: Mode::SubMod,
+                            obj_type: ObjType::Blob {
+                                path: p.file_name().unwrap().to_string_lossy().to_string(),
+                                content: vec![],
+                            },
+                            sha: Some(sha),
+                        })
+                    }
+                    None => objs.push(GitObject::write_path(
+                        p,
+                        ignore,
+                        progress,
+                        store,
+                        recurse_submodules,
+                    )?),
+                }
             }
 
             // git will always alphabetically sort objects in the tree
@@ -227,16 +560,17 @@ impl GitObject {
 
             let bytes = objs
                 .iter()
-                .flat_map(|o| o.tree_content_bytes())
-                .collect::<Vec<u8>>();
+                .map(|o| o.tree_content_bytes())
+                .collect::<anyhow::Result<Vec<_>>>()?
+                .concat();
             let mut content = format!("tree {}\0", bytes.len()).into_bytes();
             content.extend_from_slice(&bytes);
 
-            let (sha, _content) = compress_and_hash(&content)?;
+            let sha = hex::decode(store::write_object(&content, store)?)?;
 
             let path = path
                 .components()
-                .last()
+                .next_back()
                 .unwrap()
                 .as_os_str()
                 .to_string_lossy()
@@ -251,14 +585,12 @@ impl GitObject {
                 sha: Some(sha),
             })
         } else {
-            let file_content = fs::read(path).context("read of file content")?;
-            let mut content = format!("blob {}\0", file_content.len()).into_bytes();
-            content.extend_from_slice(&file_content);
-            let (sha, enc_content) = compress_and_hash(&content)?;
+            let sha = hex::decode(hash_file(path, true, store)?)?;
+            progress.tick();
 
             let path = path
                 .components()
-                .last()
+                .next_back()
                 .unwrap()
                 .as_os_str()
                 .to_string_lossy()
@@ -267,7 +599,7 @@ impl GitObject {
                 mode: Mode::FileBlob,
                 obj_type: ObjType::Blob {
                     path,
-                    content: enc_content,
+                    content: vec![],
                 },
                 sha: Some(sha),
             })
@@ -289,7 +621,9 @@ impl GitObject {
     //         + self.sha.as_ref().map_or(1, |s| s.len())
     // }
 
-    pub fn tree_content_bytes(&self) -> Vec<u8> {
+    /// The entry as it is stored in a tree, errors for a tombstone since a deletion can't
+    /// be written.
+    pub fn tree_content_bytes(&self) -> anyhow::Result<Vec<u8>> {
         // [mode] [Object name]\0[SHA-1 in binary format]
         let path = match &self.obj_type {
             ObjType::Blob { path, .. } => path,
@@ -297,12 +631,15 @@ impl GitObject {
                 path: Some(path), ..
             } => path,
             ObjType::Tree { .. } => todo!(),
-            ObjType::Commit => todo!(),
+            ObjType::Commit { .. } => todo!(),
         };
-        // I think the sha.map_or("\0", ...) will equate to a deleted object
+        let sha = self
+            .sha
+            .as_ref()
+            .with_context(|| format!("can't write '{}' to a tree, it is being deleted", path))?;
         let mut bytes = format!("{} {}\0", (self.mode as usize), path).into_bytes();
-        bytes.extend_from_slice(self.sha.as_ref().map_or(b"\0", |s| s));
-        bytes
+        bytes.extend_from_slice(sha);
+        Ok(bytes)
     }
 
     pub fn as_path_str(&self) -> &str {
@@ -312,17 +649,23 @@ impl GitObject {
                 path: Some(path), ..
             } => path,
             ObjType::Tree { .. } => todo!(),
-            ObjType::Commit => todo!(),
+            ObjType::Commit { .. } => todo!(),
         }
     }
 
-    pub fn to_full_string(&self) -> String {
+    /// Renders `<mode> <type> <sha> <path>`, coloring the path by mode when `color` is set.
+    ///
+    /// With `abbrev` the SHA is shortened to the shortest unambiguous prefix of at least
+    /// that many characters.
+    pub fn to_full_string(&self, color: bool, abbrev: Option<usize>) -> String {
         let mut res = String::new();
         write!(res, "{}", self.mode as usize).expect("valid to write to a string");
-        write!(res, " {}", self.obj_type).expect("valid to write to a string");
+        write!(res, " {}", self.mode.obj_name()).expect("valid to write to a string");
 
         if let Some(sha) = &self.sha {
-            write!(res, " {}", hex::encode(sha)).expect("valid to write to a string");
+            let sha = hex::encode(sha);
+            let sha = abbrev.map_or(sha.clone(), |len| rev::abbrev(&sha, len));
+            write!(res, " {}", sha).expect("valid to write to a string");
         }
 
         if let ObjType::Blob { path, .. }
@@ -330,28 +673,305 @@ impl GitObject {
             path: Some(path), ..
         } = &self.obj_type
         {
-            write!(res, " {}", path).expect("valid to write to a string");
+            write!(res, " {}", paint(self.mode, path, color)).expect("valid to write to a string");
         }
 
         res
     }
 }
 
+/// The repository directory of `dir` if it is the root of another repository, one with its
+/// own `.idiot` or `.git` (a directory, or a file with a `gitdir: <path>` line like
+/// submodules get).
+pub fn submodule_repo(dir: &Path) -> anyhow::Result<Option<PathBuf>> {
+    if !dir.is_dir() {
+        return Ok(None);
+    }
+    Ok(Some(if dir.join(".idiot").is_dir() {
+        dir.join(".idiot")
+    } else if dir.join(".git").is_dir() {
+        dir.join(".git")
+    } else if let Ok(link) = fs::read_to_string(dir.join(".git")) {
+        let gitdir = link
+            .trim()
+            .strip_prefix("gitdir: ")
+            .with_context(|| format!("'{}' is not a gitdir link", dir.join(".git").display()))?;
+        dir.join(gitdir)
+    } else {
+        return Ok(None);
+    }))
+}
+
+/// The commit checked out in `dir` if it is the root of another repository, see
+/// [`submodule_repo`].
+fn submodule_head(dir: &Path) -> anyhow::Result<Option<Vec<u8>>> {
+    let Some(repo) = submodule_repo(dir)? else {
+        return Ok(None);
+    };
+
+    let mut name = "HEAD".to_string();
+    let sha = loop {
+        let content = fs::read_to_string(repo.join(&name)).with_context(|| {
+            format!(
+                "submodule '{}' does not have a commit checked out",
+                dir.display()
+            )
+        })?;
+        match content.trim().strip_prefix("ref: ") {
+            Some(target) => name = target.to_string(),
+            None => break content.trim().to_string(),
+        }
+    };
+    hex::decode(&sha)
+        .ok()
+        .filter(|sha| sha.len() == SHA_SIZE)
+        .map(Some)
+        .with_context(|| format!("submodule '{}' has a bad HEAD '{}'", dir.display(), sha))
+}
+
 fn usize_from_bytes(bytes: &[u8]) -> anyhow::Result<usize> {
     String::from_utf8(bytes.to_vec())?
         .parse()
         .with_context(|| format!("invalid number {}", String::from_utf8_lossy(bytes)))
 }
 
-/// Returns the bytes of the SHA1 hash and the compressed `bytes`.
-///
-/// The hash is taken from the result of compressing `bytes`.
-fn compress_and_hash(bytes: &[u8]) -> anyhow::Result<(Vec<u8>, Vec<u8>)> {
-    let mut enc_content = vec![];
-    ZlibEncoder::new(bytes, Compression::default()).read_to_end(&mut enc_content)?;
-
-    let mut hasher = Sha1::new();
-    hasher.update(&bytes);
-    let sha = hasher.finalize().to_vec();
-    Ok((sha, enc_content))
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::{blob, temp_dir, temp_repo, tree};
+
+    /// Snapshot the work tree at `top` like `write-tree` does, returns the tree's SHA.
+    fn write_tree(top: &Path) -> String {
+        let mut progress = Progress::new("Hashing files", false, true);
+        let tree = GitObject::write_path(top, &Ignore::from_root(top), &mut progress, false, false);
+        hex::encode(tree.unwrap().sha.unwrap())
+    }
+
+    #[test]
+    fn write_tree_of_an_empty_directory_is_the_empty_tree() {
+        let top = temp_repo("empty-tree");
+        assert_eq!(write_tree(&top), EMPTY_TREE_SHA);
+        assert_eq!(
+            store::read_object(EMPTY_TREE_SHA).unwrap(),
+            b"tree 0\0".to_vec()
+        );
+        assert!(store::object_path(EMPTY_TREE_SHA).unwrap().is_file());
+    }
+
+    #[test]
+    fn write_tree_in_two_repos_uses_each_ones_gitignore() {
+        let first = temp_repo("ignore-first");
+        fs::write(first.join(".gitignore"), "*.log\n").unwrap();
+        fs::write(first.join("a.log"), "one").unwrap();
+        fs::write(first.join("b.txt"), "two").unwrap();
+        let first_tree = write_tree(&first);
+
+        let second = temp_repo("ignore-second");
+        fs::write(second.join(".gitignore"), "*.txt\n").unwrap();
+        fs::write(second.join("a.log"), "one").unwrap();
+        fs::write(second.join("b.txt"), "two").unwrap();
+        let second_tree = write_tree(&second);
+
+        let (log, txt) = (blob("one"), blob("two"));
+        let ignore_logs = (Mode::FileBlob, ".gitignore", &blob("*.log\n") as &str);
+        assert_eq!(
+            first_tree,
+            tree(&[ignore_logs, (Mode::FileBlob, "b.txt", &txt)])
+        );
+        let ignore_txts = (Mode::FileBlob, ".gitignore", &blob("*.txt\n") as &str);
+        assert_eq!(
+            second_tree,
+            tree(&[ignore_txts, (Mode::FileBlob, "a.log", &log)])
+        );
+    }
+
+    #[test]
+    fn commit_parser_keeps_a_gpg_signature_apart() {
+        let text = "tree 3c2e5b665ad11db338f082f919d1e8de3e954d07
+parent b8ffc332bae44f00e35ca8d2a2b7bcdbe94f5d33
+author A U Thor <author@example.com> 1112911993 -0700
+committer C O Mitter <committer@example.com> 1112911993 -0700
+gpgsig -----BEGIN PGP SIGNATURE-----
+ 
+ iQEzBAABCAAdFiEE
+ =abcd
+ -----END PGP SIGNATURE-----
+
+Signed
+
+With a body.
+";
+        let obj = format!("commit {}\0{}", text.len(), text);
+        let ObjType::Commit {
+            tree,
+            parents,
+            headers,
+            signature,
+            message,
+        } = GitObject::try_from_bytes(obj.as_bytes()).unwrap().obj_type
+        else {
+            panic!("not parsed as a commit")
+        };
+        assert_eq!(tree, "3c2e5b665ad11db338f082f919d1e8de3e954d07");
+        assert_eq!(parents, ["b8ffc332bae44f00e35ca8d2a2b7bcdbe94f5d33"]);
+        assert_eq!(
+            headers.iter().map(|(key, _)| key).collect::<Vec<_>>(),
+            ["author", "committer"]
+        );
+        assert_eq!(
+            signature.unwrap(),
+            "-----BEGIN PGP SIGNATURE-----\n\niQEzBAABCAAdFiEE\n=abcd\n-----END PGP SIGNATURE-----"
+        );
+        assert_eq!(message, "Signed\n\nWith a body.\n");
+    }
+
+    #[test]
+    fn tree_parser_reads_exactly_20_sha_bytes_per_entry() {
+        // SHAs full of separators and digits, right up against the next entry's mode
+        let a_sha = [b'\0'; SHA_SIZE];
+        let mut b_sha = [b' '; SHA_SIZE];
+        b_sha[..6].copy_from_slice(b"100644");
+        let mut body = b"100644 a\0".to_vec();
+        body.extend(a_sha);
+        body.extend(b"40000 b\0");
+        body.extend(b_sha);
+        let mut obj = format!("tree {}\0", body.len()).into_bytes();
+        obj.extend(&body);
+
+        let ObjType::Tree { objs, .. } = GitObject::try_from_bytes(&obj).unwrap().obj_type else {
+            panic!("not parsed as a tree")
+        };
+        let entries = objs
+            .iter()
+            .map(|o| (o.mode as usize, o.as_path_str(), o.sha.clone().unwrap()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            entries,
+            [(100644, "a", a_sha.to_vec()), (40000, "b", b_sha.to_vec())]
+        );
+
+        // One byte short of the last SHA
+        let short = [&obj[..obj.len() - 1]].concat();
+        let err = GitObject::try_from_bytes(&short).unwrap_err();
+        assert!(
+            format!("{:#}", err).contains("truncated sha for 'b'"),
+            "{:#}",
+            err
+        );
+    }
+
+    #[test]
+    fn write_tree_is_the_same_whatever_order_entries_are_created_in() {
+        // `a-b` < `a.txt` < `a/` < `b` in git's order, `a` is a directory
+        let files: [(&str, &str); 4] = [("a-b", "4"), ("a.txt", "2"), ("a/c", "3"), ("b", "1")];
+        let forward = temp_repo("order-forward");
+        let backward = temp_repo("order-backward");
+        for (top, files) in [
+            (&forward, files.iter().collect::<Vec<_>>()),
+            (&backward, files.iter().rev().collect()),
+        ] {
+            for (path, content) in files {
+                fs::create_dir_all(top.join(path).parent().unwrap()).unwrap();
+                fs::write(top.join(path), content).unwrap();
+            }
+        }
+
+        // From `git write-tree`
+        let sha = "31c27fec19de5bf586be1a628e46f706b8b6e6c0";
+        assert_eq!(write_tree(&forward), sha);
+        assert_eq!(write_tree(&backward), sha);
+    }
+
+    #[test]
+    fn normalize_repo_path_keeps_paths_inside_the_work_tree() {
+        // Relative to the current directory, which the test harness leaves alone
+        let cwd = std::env::current_dir().unwrap();
+        assert_eq!(normalize_repo_path("./foo").unwrap(), "foo");
+        assert_eq!(normalize_repo_path("a/../b").unwrap(), "b");
+        assert_eq!(normalize_repo_path("a/./b/").unwrap(), "a/b");
+        assert_eq!(normalize_repo_path(".").unwrap(), "");
+        let inside = cwd.join("dir/file");
+        assert_eq!(
+            normalize_repo_path(&inside.to_string_lossy()).unwrap(),
+            "dir/file"
+        );
+
+        for path in ["../escape", "a/../../escape", "/etc/passwd"] {
+            let err = normalize_repo_path(path).unwrap_err();
+            assert!(
+                err.to_string().contains("outside the repository"),
+                "{}",
+                path
+            );
+        }
+    }
+
+    #[test]
+    fn tombstones_are_carried_but_never_written() {
+        temp_repo("tombstone");
+        let entry = |name: &str, sha: Option<Vec<u8>>| GitObject {
+            mode: Mode::FileBlob,
+            obj_type: ObjType::Blob {
+                path: name.to_string(),
+                content: vec![],
+            },
+            sha,
+        };
+        let kept = hex::decode(blob("kept")).unwrap();
+        // A pending change can hold a deletion next to other entries and show it
+        let mut change = [entry("b", Some(kept)), entry("a", None)];
+        change.sort();
+        assert_eq!(change[0].to_full_string(false, None), "100644 blob a");
+        assert!(change[1].tree_content_bytes().is_ok());
+
+        let err = change[0].tree_content_bytes().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "can't write 'a' to a tree, it is being deleted"
+        );
+    }
+
+    #[test]
+    fn the_last_matching_ignore_pattern_wins() {
+        let ignored = |gitignore: &str, path: &str| {
+            let top = temp_dir("ignore-order");
+            fs::write(top.join(".gitignore"), gitignore).unwrap();
+            Ignore::from_root(&top).is_ignored(Path::new(path))
+        };
+        assert!(ignored("*.log\n!keep.log\n", "x.log"));
+        assert!(!ignored("*.log\n!keep.log\n", "keep.log"));
+        assert!(ignored("!keep.log\n*.log\n", "keep.log"));
+        assert!(!ignored("*.log\n!*.log\n", "dir/x.log"));
+        // Nothing inside an ignored directory can be re-included
+        assert!(ignored("build/\n!build/keep\n", "build/keep"));
+    }
+
+    #[test]
+    fn write_path_keeps_only_names_and_shas() {
+        let top = temp_repo("write-path-memory");
+        for i in 0..50 {
+            fs::create_dir_all(top.join(format!("dir{}", i % 5))).unwrap();
+            fs::write(
+                top.join(format!("dir{}/file{}", i % 5, i)),
+                vec![b'x'; 4096],
+            )
+            .unwrap();
+        }
+        let mut progress = Progress::new("Hashing files", false, true);
+        let ignore = Ignore::from_root(&top);
+        let tree = GitObject::write_path(&top, &ignore, &mut progress, false, false).unwrap();
+
+        fn check(obj: &GitObject) -> usize {
+            assert!(obj.sha.is_some());
+            match &obj.obj_type {
+                ObjType::Blob { content, .. } => {
+                    assert!(content.is_empty());
+                    1
+                }
+                ObjType::Tree { objs, .. } => objs.iter().map(check).sum(),
+                ObjType::Commit { .. } => unreachable!(),
+            }
+        }
+        assert_eq!(check(&tree), 50);
+    }
 }
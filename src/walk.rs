@@ -0,0 +1,155 @@
+//! Walking commit history, shared by everything that follows parents.
+use std::collections::{HashSet, VecDeque};
+
+use crate::{
+    store::read_object,
+    tree::{GitObject, ObjType},
+};
+
+/// A commit reached by a [`CommitWalk`].
+#[derive(Debug)]
+pub struct WalkedCommit {
+    pub sha: String,
+    pub parents: Vec<String>,
+}
+
+/// Walks commits breadth first from a start commit, nearest first.
+///
+/// Each commit is visited once however many paths lead to it, so a corrupt history with
+/// a cycle can't loop forever. Following only first parents a cycle is an error.
+#[derive(Debug)]
+pub struct CommitWalk {
+    queue: VecDeque<String>,
+    seen: HashSet<String>,
+    first_parent: bool,
+    max_count: Option<usize>,
+}
+
+impl CommitWalk {
+    pub fn new(start: &str) -> Self {
+        Self {
+            queue: VecDeque::from([start.to_string()]),
+            seen: HashSet::from([start.to_string()]),
+            first_parent: false,
+            max_count: None,
+        }
+    }
+
+    /// Only follow each commit's first parent, the mainline of a history with merges.
+    pub fn first_parent(mut self, first_parent: bool) -> Self {
+        self.first_parent = first_parent;
+        self
+    }
+
+    /// Stop after `max_count` commits.
+    pub fn max_count(mut self, max_count: Option<usize>) -> Self {
+        self.max_count = max_count;
+        self
+    }
+}
+
+impl Iterator for CommitWalk {
+    type Item = anyhow::Result<WalkedCommit>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.max_count == Some(0) {
+            return None;
+        }
+        let sha = self.queue.pop_front()?;
+        self.max_count = self.max_count.map(|count| count - 1);
+
+        let obj = match read_object(&sha) {
+            Ok(obj) => obj,
+            Err(e) => return Some(Err(e)),
+        };
+        let parsed = match GitObject::try_from_bytes(&obj) {
+            Ok(parsed) => parsed,
+            Err(e) => return Some(Err(e.context(format!("reading commit {}", sha)))),
+        };
+        let ObjType::Commit { parents, .. } = parsed.obj_type else {
+            return Some(Err(anyhow::anyhow!("{} is not a commit", sha)));
+        };
+        let follow = if self.first_parent {
+            &parents[..parents.len().min(1)]
+        } else {
+            &parents[..]
+        };
+        for parent in follow {
+            if self.seen.insert(parent.clone()) {
+                self.queue.push_back(parent.clone());
+            } else if self.first_parent {
+                // A first parent chain never meets itself unless the history loops
+                self.queue.clear();
+                return Some(Err(anyhow::anyhow!(
+                    "history has a cycle, {} is its own ancestor",
+                    parent
+                )));
+            }
+        }
+        Some(Ok(WalkedCommit { sha, parents }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::{
+        compress_obj, store,
+        testutil::{commit, temp_repo},
+        tree::EMPTY_TREE_SHA,
+    };
+
+    fn shas(walk: CommitWalk) -> anyhow::Result<Vec<String>> {
+        walk.map(|c| Ok(c?.sha)).collect()
+    }
+
+    #[test]
+    fn walk_stops_at_a_cyclic_parent() {
+        temp_repo("walk-cycle");
+        // No real commit can be its own ancestor, so store one under a name it doesn't
+        // hash to
+        let fake = "1".repeat(40);
+        let child = commit(EMPTY_TREE_SHA, &[&fake], "child");
+        let text = format!("tree {}\nparent {}\n\nloop\n", EMPTY_TREE_SHA, child);
+        let obj = format!("commit {}\0{}", text.len(), text);
+        let path = store::object_path(&fake).unwrap();
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, compress_obj(obj.as_bytes(), false).unwrap()).unwrap();
+
+        assert_eq!(shas(CommitWalk::new(&fake)).unwrap(), [fake.clone(), child]);
+        let err = shas(CommitWalk::new(&fake).first_parent(true)).unwrap_err();
+        assert!(format!("{:#}", err).contains("has a cycle"), "{:#}", err);
+    }
+
+    #[test]
+    fn walk_follows_first_parents_up_to_max_count() {
+        temp_repo("walk-merge");
+        let root = commit(EMPTY_TREE_SHA, &[], "root");
+        let left = commit(EMPTY_TREE_SHA, &[&root], "left");
+        let right = commit(EMPTY_TREE_SHA, &[&root], "right");
+        let merge = commit(EMPTY_TREE_SHA, &[&left, &right], "merge");
+
+        let all = [&merge, &left, &right, &root].map(String::clone);
+        assert_eq!(shas(CommitWalk::new(&merge)).unwrap(), all);
+        let mainline = [&merge, &left, &root].map(String::clone);
+        assert_eq!(
+            shas(CommitWalk::new(&merge).first_parent(true)).unwrap(),
+            mainline
+        );
+        assert_eq!(
+            shas(CommitWalk::new(&merge).max_count(Some(2))).unwrap(),
+            all[..2]
+        );
+        assert_eq!(
+            shas(
+                CommitWalk::new(&merge)
+                    .first_parent(true)
+                    .max_count(Some(0))
+            )
+            .unwrap(),
+            Vec::<String>::new()
+        );
+    }
+}
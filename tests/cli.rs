@@ -0,0 +1,1110 @@
+//! Runs the `idiot` binary in scratch repositories, for what commands print and how they
+//! exit.
+use std::{
+    fs,
+    io::{Read, Write},
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    process::{Command, Output, Stdio},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use flate2::{read::ZlibDecoder, write::ZlibEncoder, Compression};
+use sha1::{Digest, Sha1};
+
+/// A repository with a work tree under the system temp directory.
+struct Repo {
+    top: PathBuf,
+}
+
+impl Repo {
+    /// A new empty repository for the test `name`.
+    fn new(name: &str) -> Repo {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let top = std::env::temp_dir().join(format!(
+            "idiot-cli-{}-{}-{}",
+            name,
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::SeqCst)
+        ));
+        let _ = fs::remove_dir_all(&top);
+        fs::create_dir_all(&top).unwrap();
+        let repo = Repo { top };
+        repo.ok(&["init"]);
+        repo
+    }
+
+    /// A new repository in the directory `path` of this work tree.
+    fn nested(&self, path: &str) -> Repo {
+        let repo = Repo {
+            top: self.top.join(path),
+        };
+        fs::create_dir_all(&repo.top).unwrap();
+        repo.ok(&["init", "-q"]);
+        repo
+    }
+
+    /// Run `idiot args` at the top of the work tree with `stdin`.
+    fn run_with(&self, args: &[&str], stdin: &[u8]) -> Output {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_git-starter-rust"))
+            .args(args)
+            .current_dir(&self.top)
+            .env("NO_COLOR", "1")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        child.stdin.take().unwrap().write_all(stdin).unwrap();
+        child.wait_with_output().unwrap()
+    }
+
+    fn run(&self, args: &[&str]) -> Output {
+        self.run_with(args, b"")
+    }
+
+    /// The stdout of `idiot args`, which has to succeed.
+    fn ok(&self, args: &[&str]) -> String {
+        let out = self.run(args);
+        assert!(out.status.success(), "{:?}: {}", args, stderr(&out));
+        String::from_utf8(out.stdout).unwrap()
+    }
+
+    /// Write `content` to the file `path` in the work tree.
+    fn write(&self, path: &str, content: impl AsRef<[u8]>) {
+        let path = self.top.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, content).unwrap();
+    }
+
+    fn exists(&self, path: &str) -> bool {
+        self.top.join(path).exists()
+    }
+
+    /// Store a loose object of `kind` with `content` like git does, returns its SHA.
+    fn object(&self, kind: &str, content: &[u8]) -> String {
+        let obj = [format!("{} {}\0", kind, content.len()).as_bytes(), content].concat();
+        let sha = hex::encode(Sha1::digest(&obj));
+        let path = self.top.join(object_path(&sha));
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        let mut encoder = ZlibEncoder::new(vec![], Compression::default());
+        encoder.write_all(&obj).unwrap();
+        fs::write(path, encoder.finish().unwrap()).unwrap();
+        sha
+    }
+
+    fn blob(&self, content: &str) -> String {
+        self.object("blob", content.as_bytes())
+    }
+
+    /// Store a tree of `(mode, name, sha)` entries, in the order given.
+    fn tree(&self, entries: &[(&str, &str, &str)]) -> String {
+        let mut content = vec![];
+        for (mode, name, sha) in entries {
+            content.extend(format!("{} {}\0", mode, name).as_bytes());
+            content.extend(hex::decode(sha).unwrap());
+        }
+        self.object("tree", &content)
+    }
+
+    fn commit(&self, tree: &str, parents: &[&str], message: &str) -> String {
+        let mut text = format!("tree {}\n", tree);
+        for parent in parents {
+            text.push_str(&format!("parent {}\n", parent));
+        }
+        text.push_str("author A U Thor <author@example.com> 1112911993 -0700\n");
+        text.push_str("committer C O Mitter <committer@example.com> 1112911993 -0700\n");
+        text.push_str(&format!("\n{}\n", message));
+        self.object("commit", text.as_bytes())
+    }
+
+    /// Store an annotated tag `name` of the commit `commit`.
+    fn tag(&self, commit: &str, name: &str) -> String {
+        let text = format!(
+            "object {}\ntype commit\ntag {}\ntagger A U Thor <author@example.com> 1112911993 -0700\n\n{}\n",
+            commit, name, name
+        );
+        self.object("tag", text.as_bytes())
+    }
+}
+
+/// Where the loose object `sha` is, from the top of the work tree.
+fn object_path(sha: &str) -> String {
+    format!(".idiot/objects/{}/{}", &sha[..2], &sha[2..])
+}
+
+/// `len` bytes of every value, shuffled enough that they aren't UTF-8 and don't compress.
+fn noise(len: usize) -> Vec<u8> {
+    let mut state = 1u32;
+    (0..len)
+        .map(|_| {
+            state = state.wrapping_mul(1664525).wrapping_add(1013904223);
+            (state >> 24) as u8
+        })
+        .collect()
+}
+
+fn stderr(out: &Output) -> String {
+    String::from_utf8_lossy(&out.stderr).into_owned()
+}
+
+#[test]
+fn ls_tree_of_a_commit_names_its_tree() {
+    let repo = Repo::new("ls-tree-commit");
+    let a = repo.blob("one\n");
+    let tree = repo.tree(&[("100644", "a", &a)]);
+    let commit = repo.commit(&tree, &[], "first");
+
+    let listing = format!("100644 blob {} a\n", a);
+    let header = format!("tree 29 (SHA: {})\n", tree);
+    assert_eq!(
+        repo.ok(&["ls-tree", &commit]),
+        format!("{}{}", header, listing)
+    );
+    assert_eq!(
+        repo.ok(&["ls-tree", &tree]),
+        format!("{}{}", header, listing)
+    );
+    assert_eq!(repo.ok(&["-q", "ls-tree", &commit]), listing);
+}
+
+#[test]
+fn hash_object_hashes_each_file_in_order() {
+    let repo = Repo::new("hash-object-files");
+    repo.write("a", "hello world\n");
+    repo.write("b", "");
+    repo.write("c", "a\0b\u{ff}");
+
+    // From `git hash-object a b c`
+    let shas = [
+        "3b18e512dba79e4c8300dd08aeb37f8e728b8dad",
+        "e69de29bb2d1d6434b8b29ae775ad8c2e48c5391",
+        "26e3594715764bf820230bf88a788dbb4e80ce45",
+    ];
+    let out = repo.ok(&["hash-object", "a", "b", "c"]);
+    assert_eq!(out, format!("{}\n", shas.join("\n")));
+    assert!(shas.iter().all(|sha| !repo.exists(&object_path(sha))));
+    assert_eq!(repo.ok(&["hash-object", "-w", "a", "b", "c"]), out);
+    assert!(shas.iter().all(|sha| repo.exists(&object_path(sha))));
+}
+
+#[test]
+fn quiet_leaves_only_the_essential_output() {
+    let repo = Repo::new("quiet");
+    assert_eq!(repo.ok(&["init", "sub"]), "Initialized git directory\n");
+    assert_eq!(repo.ok(&["init", "-q", "other"]), "");
+    assert!(repo.exists("other/.idiot/HEAD"));
+    fs::remove_dir_all(repo.top.join("sub")).unwrap();
+    fs::remove_dir_all(repo.top.join("other")).unwrap();
+
+    repo.write("f", "hi\n");
+    let blob = "45b983be36b73c0788dc9cbcb76cbb80fc7bb057";
+    assert_eq!(
+        repo.ok(&["hash-object", "-v", "f"]),
+        format!("SHA: {}\n", blob)
+    );
+    assert_eq!(
+        repo.ok(&["-q", "hash-object", "-v", "f"]),
+        format!("{}\n", blob)
+    );
+    let tree = "df55a7dce59d040dc7819c1e241082965a80ebd9";
+    let verbose = repo.ok(&["write-tree", "-v"]);
+    assert!(
+        verbose.starts_with(&format!("tree 29 (SHA: {})\n", tree)),
+        "{}",
+        verbose
+    );
+    assert_eq!(
+        repo.ok(&["write-tree", "-v", "--quiet"]),
+        format!("{}\n", tree)
+    );
+    assert_eq!(repo.ok(&["pack-refs"]), "Packed 0 refs\n");
+    assert_eq!(repo.ok(&["pack-refs", "-q"]), "");
+}
+
+#[test]
+fn write_tree_prints_only_the_sha() {
+    let repo = Repo::new("write-tree-sha");
+    repo.write("f", "hi\n");
+    repo.write("dir/g", "there\n");
+    let out = repo.ok(&["write-tree"]);
+    assert_eq!(out.len(), 41, "{:?}", out);
+    assert!(out[..40]
+        .bytes()
+        .all(|b| b.is_ascii_hexdigit() && !b.is_ascii_uppercase()));
+    assert!(out.ends_with('\n'));
+    assert!(repo.exists(&object_path(&out[..40])));
+}
+
+#[test]
+fn hash_object_prints_the_bare_hash() {
+    let repo = Repo::new("hash-object-bare");
+    repo.write("f", "hello world\n");
+    let sha = "3b18e512dba79e4c8300dd08aeb37f8e728b8dad";
+    assert_eq!(repo.ok(&["hash-object", "f"]), format!("{}\n", sha));
+    assert_eq!(
+        repo.ok(&["hash-object", "--verbose", "f"]),
+        format!("SHA: {}\n", sha)
+    );
+}
+
+#[test]
+fn cat_file_prints_a_large_binary_blob_byte_for_byte() {
+    let repo = Repo::new("cat-file-large");
+    let content = noise(3 << 20);
+    repo.write("big.bin", &content);
+    let sha = repo.ok(&["hash-object", "-w", "big.bin"]);
+
+    let out = repo.run(&["cat-file", "-p", sha.trim()]);
+    assert!(out.status.success(), "{}", stderr(&out));
+    assert!(out.stdout == content, "{} bytes differ", out.stdout.len());
+}
+
+#[test]
+fn batch_check_reports_each_sha_or_missing() {
+    let repo = Repo::new("batch-check");
+    let blob = repo.blob("hello");
+    let tree = repo.tree(&[("100644", "a", &blob)]);
+    let commit = repo.commit(&tree, &[], "first");
+    let missing = "0123456789abcdef0123456789abcdef01234567";
+
+    let stdin = format!("{}\n{}\n{}\n{}\n", blob, missing, tree, commit);
+    let out = repo.run_with(&["cat-file", "--batch-check"], stdin.as_bytes());
+    assert!(out.status.success(), "{}", stderr(&out));
+    let expected = [
+        format!("{} blob 5", blob),
+        format!("{} missing", missing),
+        format!("{} tree 29", tree),
+        format!("{} commit 169", commit),
+    ];
+    assert_eq!(
+        String::from_utf8(out.stdout).unwrap(),
+        format!("{}\n", expected.join("\n"))
+    );
+}
+
+#[test]
+fn rev_parse_peels_to_a_tree_or_commit() {
+    let repo = Repo::new("peel");
+    let tree = repo.tree(&[("100644", "a", &repo.blob("one"))]);
+    let commit = repo.commit(&tree, &[], "first");
+    let tag = repo.tag(&commit, "v1.0");
+    repo.ok(&["update-ref", "HEAD", &commit]);
+    repo.ok(&["update-ref", "refs/tags/v1.0", &tag]);
+
+    assert_eq!(repo.ok(&["rev-parse", "v1.0"]), format!("{}\n", tag));
+    assert_eq!(
+        repo.ok(&["rev-parse", "v1.0^{commit}"]),
+        format!("{}\n", commit)
+    );
+    assert_eq!(
+        repo.ok(&["rev-parse", "v1.0^{tree}"]),
+        format!("{}\n", tree)
+    );
+    assert_eq!(
+        repo.ok(&["rev-parse", "HEAD^{tree}"]),
+        format!("{}\n", tree)
+    );
+    assert_eq!(
+        repo.ok(&["rev-parse", "HEAD^{commit}"]),
+        format!("{}\n", commit)
+    );
+    assert_eq!(repo.ok(&["cat-file", "-t", "HEAD^{tree}"]), "tree\n");
+    let out = repo.run(&["rev-parse", &format!("{}^{{commit}}", tree)]);
+    assert!(!out.status.success());
+}
+
+#[test]
+fn abbrev_shortens_shas_while_they_stay_unique() {
+    let repo = Repo::new("abbrev");
+    // Two blobs whose SHAs start with the same 4 digits, found by brute force
+    let mut seen = std::collections::HashMap::new();
+    let (first, second) = (0..)
+        .find_map(|i| {
+            let content = format!("blob {}", i);
+            let obj = format!("blob {}\0{}", content.len(), content);
+            let prefix = hex::encode(Sha1::digest(obj.as_bytes()))[..4].to_string();
+            seen.insert(prefix, content.clone())
+                .map(|other| (other, content))
+        })
+        .unwrap();
+    let (a, b) = (repo.blob(&first), repo.blob(&second));
+    let c = repo.blob("unique");
+    let tree = repo.tree(&[
+        ("100644", "a", &a),
+        ("100644", "b", &b),
+        ("100644", "c", &c),
+    ]);
+
+    let common = a.bytes().zip(b.bytes()).take_while(|(x, y)| x == y).count();
+    let listing = repo.ok(&["-q", "--abbrev=4", "ls-tree", &tree]);
+    let expected = format!(
+        "100644 blob {} a\n100644 blob {} b\n100644 blob {} c\n",
+        &a[..common + 1],
+        &b[..common + 1],
+        &c[..4]
+    );
+    assert_eq!(listing, expected);
+    let listing = repo.ok(&["-q", "--abbrev", "ls-tree", &tree]);
+    assert!(
+        listing.contains(&format!(" {} c\n", &c[..7])),
+        "{}",
+        listing
+    );
+    assert!(listing.contains(&format!(" {} a\n", &a[..7.max(common + 1)])));
+    assert_eq!(
+        repo.ok(&["-q", "ls-tree", &tree])
+            .lines()
+            .next()
+            .unwrap()
+            .len(),
+        54
+    );
+}
+
+#[test]
+fn describe_names_commits_after_the_nearest_tag() {
+    let repo = Repo::new("describe");
+    let empty = repo.tree(&[]);
+    let root = repo.commit(&empty, &[], "root");
+    let first = repo.commit(&empty, &[&root], "first");
+    let second = repo.commit(&empty, &[&first], "second");
+    let third = repo.commit(&empty, &[&second], "third");
+    repo.ok(&["update-ref", "HEAD", &third]);
+    repo.ok(&["update-ref", "refs/tags/v1.0", &repo.tag(&first, "v1.0")]);
+
+    assert_eq!(
+        repo.ok(&["describe", "HEAD"]),
+        format!("v1.0-2-g{}\n", &third[..7])
+    );
+    assert_eq!(
+        repo.ok(&["describe", &second]),
+        format!("v1.0-1-g{}\n", &second[..7])
+    );
+    assert_eq!(repo.ok(&["describe", &first]), "v1.0\n");
+    assert!(!repo.run(&["describe", &root]).status.success());
+    assert_eq!(
+        repo.ok(&["describe", "--always", &root]),
+        format!("{}\n", &root[..7])
+    );
+
+    // A lightweight tag nearer to HEAD wins
+    repo.ok(&["update-ref", "refs/tags/v1.1", &second]);
+    assert_eq!(
+        repo.ok(&["describe", "HEAD"]),
+        format!("v1.1-1-g{}\n", &third[..7])
+    );
+}
+
+#[test]
+fn allow_unknown_type_reads_a_damaged_header() {
+    let repo = Repo::new("unknown-type");
+    let sha = repo.object("frob", b"abc");
+    assert!(!repo.run(&["cat-file", "-t", &sha]).status.success());
+    assert!(!repo.run(&["cat-file", "-s", &sha]).status.success());
+    assert!(!repo.run(&["cat-file", "-p", &sha]).status.success());
+
+    assert_eq!(
+        repo.ok(&["cat-file", "-t", "--allow-unknown-type", &sha]),
+        "frob\n"
+    );
+    assert_eq!(
+        repo.ok(&["cat-file", "-s", "--allow-unknown-type", &sha]),
+        "3\n"
+    );
+}
+
+#[test]
+fn unpack_objects_writes_back_what_pack_objects_packed() {
+    let source = Repo::new("unpack-source");
+    let a = source.blob("one");
+    let tree = source.tree(&[("100644", "a", &a)]);
+    let commit = source.commit(&tree, &[], "first");
+    let shas = [a, tree, commit];
+    let out = source.run_with(&["pack-objects", "--stdout"], shas.join("\n").as_bytes());
+    assert!(out.status.success(), "{}", stderr(&out));
+    let pack = out.stdout;
+
+    let dest = Repo::new("unpack-dest");
+    let out = dest.run_with(&["unpack-objects"], &pack);
+    assert!(out.status.success(), "{}", stderr(&out));
+    for sha in &shas {
+        let path = object_path(sha);
+        assert!(dest.exists(&path), "{} wasn't unpacked", sha);
+        let kind = source.ok(&["cat-file", "-t", sha]);
+        assert_eq!(dest.ok(&["cat-file", "-t", sha]), kind);
+        assert_eq!(
+            dest.ok(&["cat-file", "-p", sha]),
+            source.ok(&["cat-file", "-p", sha])
+        );
+    }
+
+    // And from a file
+    let again = Repo::new("unpack-file");
+    again.write("objects.pack", &pack);
+    again.ok(&["unpack-objects", "objects.pack"]);
+    assert!(shas.iter().all(|sha| again.exists(&object_path(sha))));
+}
+
+#[test]
+fn ls_tree_relative_strips_the_directory() {
+    let repo = Repo::new("ls-tree-relative");
+    let blob = repo.blob("x");
+    let lib = repo.tree(&[("100644", "x.rs", &blob)]);
+    let src = repo.tree(&[("40000", "lib", &lib), ("100644", "main.rs", &blob)]);
+    let top = repo.tree(&[
+        ("100644", "a", &blob),
+        ("40000", "src", &src),
+        ("100644", "srcx", &blob),
+    ]);
+
+    let listing = repo.ok(&["ls-tree", "-r", "--name-only", "--relative", "src", &top]);
+    assert_eq!(listing, "lib/x.rs\nmain.rs\n");
+    let listing = repo.ok(&["-q", "ls-tree", "-r", "--relative", "src/lib/", &top]);
+    assert_eq!(listing, format!("100644 blob {} x.rs\n", blob));
+    let listing = repo.ok(&["ls-tree", "-r", "--name-only", &top]);
+    assert_eq!(listing, "a\nsrc/lib/x.rs\nsrc/main.rs\nsrcx\n");
+}
+
+#[test]
+fn write_tree_records_a_nested_repository_as_a_gitlink() {
+    let repo = Repo::new("gitlink");
+    repo.write("a", "a\n");
+    let sub = repo.nested("lib");
+    sub.write("inside", "not in the outer tree\n");
+    let commit = sub.commit(&sub.tree(&[]), &[], "sub");
+    sub.ok(&["update-ref", "HEAD", &commit]);
+
+    let tree = repo.ok(&["write-tree"]);
+    let listing = repo.ok(&["-q", "ls-tree", "-r", tree.trim()]);
+    let a = "78981922613b2afb6025042ff6bd878ac1994e85";
+    let expected = format!("100644 blob {} a\n160000 commit {} lib\n", a, commit);
+    assert_eq!(listing, expected);
+}
+
+#[test]
+fn cat_file_prints_the_blob_at_a_path_of_head() {
+    let repo = Repo::new("cat-file-path");
+    let main = repo.blob("fn main() {}\n");
+    let src = repo.tree(&[("100644", "main.rs", &main)]);
+    let commit = repo.commit(&repo.tree(&[("40000", "src", &src)]), &[], "first");
+    repo.ok(&["update-ref", "HEAD", &commit]);
+
+    assert_eq!(
+        repo.ok(&["cat-file", "-p", "HEAD:src/main.rs"]),
+        "fn main() {}\n"
+    );
+    assert_eq!(repo.ok(&["cat-file", "-t", "HEAD:src"]), "tree\n");
+    let out = repo.run(&["cat-file", "-p", "HEAD:src/lib.rs"]);
+    assert!(!out.status.success());
+    assert!(stderr(&out).contains("src/lib.rs"), "{}", stderr(&out));
+}
+
+#[test]
+fn rev_path_syntax_walks_nested_trees() {
+    let repo = Repo::new("rev-path");
+    let file = repo.blob("deep\n");
+    let c = repo.tree(&[("100644", "file", &file)]);
+    let b = repo.tree(&[("40000", "c", &c)]);
+    let top = repo.tree(&[("40000", "b", &b), ("100644", "top", &file)]);
+    let commit = repo.commit(&top, &[], "first");
+    repo.ok(&["update-ref", "HEAD", &commit]);
+
+    assert_eq!(
+        repo.ok(&["rev-parse", "HEAD:b/c/file"]),
+        format!("{}\n", file)
+    );
+    assert_eq!(repo.ok(&["rev-parse", "HEAD:./b/c"]), format!("{}\n", c));
+    assert_eq!(
+        repo.ok(&["rev-parse", &format!("{}:b/c/", top)]),
+        format!("{}\n", c)
+    );
+    assert_eq!(repo.ok(&["rev-parse", "HEAD:"]), format!("{}\n", top));
+    assert_eq!(repo.ok(&["cat-file", "-p", "HEAD:b/c/file"]), "deep\n");
+    for missing in ["HEAD:b/x/file", "HEAD:b/c/nope", "HEAD:top/file"] {
+        let out = repo.run(&["rev-parse", missing]);
+        assert!(!out.status.success(), "{} resolved", missing);
+    }
+}
+
+#[test]
+fn blame_attributes_each_line_to_the_commit_that_added_it() {
+    let repo = Repo::new("blame");
+    let file = |content: &str| repo.tree(&[("100644", "f", &repo.blob(content))]);
+    let first = repo.commit(&file("one\ntwo\nthree\n"), &[], "first");
+    let second = repo.commit(&file("one\nTWO\nthree\nfour\n"), &[&first], "second");
+    let third = repo.commit(&file("zero\none\nTWO\nthree\nfour\n"), &[&second], "third");
+    repo.ok(&["update-ref", "HEAD", &third]);
+
+    let expected = [
+        (&third, "zero"),
+        (&first, "one"),
+        (&second, "TWO"),
+        (&first, "three"),
+        (&second, "four"),
+    ]
+    .map(|(sha, line)| format!("{} {}\n", &sha[..7], line))
+    .concat();
+    assert_eq!(repo.ok(&["blame", "f"]), expected);
+    assert!(!repo.run(&["blame", "missing"]).status.success());
+}
+
+#[test]
+fn show_ref_lists_branches_and_tags() {
+    let repo = Repo::new("show-ref");
+    let first = repo.commit(&repo.tree(&[]), &[], "first");
+    let second = repo.commit(&repo.tree(&[]), &[&first], "second");
+    repo.ok(&["update-ref", "HEAD", &second]);
+    repo.ok(&["update-ref", "refs/heads/topic", &first]);
+    let tag = repo.tag(&first, "v1.0");
+    repo.ok(&["update-ref", "refs/tags/v1.0", &tag]);
+
+    let heads = format!("{} refs/heads/master\n{} refs/heads/topic\n", second, first);
+    let tags = format!("{} refs/tags/v1.0\n", tag);
+    let all = format!("{} HEAD\n{}{}", second, heads, tags);
+    assert_eq!(repo.ok(&["show-ref"]), all);
+    assert_eq!(repo.ok(&["show-ref", "--heads"]), heads);
+    assert_eq!(repo.ok(&["show-ref", "--tags"]), tags);
+}
+
+#[test]
+fn for_each_ref_fills_in_the_format() {
+    let repo = Repo::new("for-each-ref");
+    let commit = repo.commit(&repo.tree(&[]), &[], "first");
+    repo.ok(&["update-ref", "HEAD", &commit]);
+    let tag = repo.tag(&commit, "v1.0");
+    repo.ok(&["update-ref", "refs/tags/v1.0", &tag]);
+
+    let expected = format!(
+        "{} commit\trefs/heads/master\n{} tag\trefs/tags/v1.0\n",
+        commit, tag
+    );
+    assert_eq!(repo.ok(&["for-each-ref"]), expected);
+    let format = "%(refname:short) %(objectname:short) %(objecttype) 100%%";
+    let expected = format!(
+        "master {} commit 100%\nv1.0 {} tag 100%\n",
+        &commit[..7],
+        &tag[..7]
+    );
+    assert_eq!(repo.ok(&["for-each-ref", "--format", format]), expected);
+    let tags = repo.ok(&["for-each-ref", "--format=%(refname)", "refs/tags"]);
+    assert_eq!(tags, "refs/tags/v1.0\n");
+    assert!(!repo
+        .run(&["for-each-ref", "--format=%(author)"])
+        .status
+        .success());
+}
+
+#[test]
+fn incompressible_blobs_are_stored_at_level_0() {
+    let repo = Repo::new("level-0");
+    let size = 256 << 10;
+    repo.write("noise.bin", noise(size));
+    repo.write("zeros.bin", vec![0; size]);
+    let noise = repo.ok(&["hash-object", "-w", "noise.bin"]);
+    let zeros = repo.ok(&["hash-object", "-w", "zeros.bin"]);
+
+    // FLEVEL, the top two bits of the second byte, is 0 for the fastest level
+    let stored = fs::read(repo.top.join(object_path(noise.trim()))).unwrap();
+    assert_eq!(stored[0], 0x78);
+    assert_eq!(stored[1] >> 6, 0, "{:#x}", stored[1]);
+    assert!(stored.len() < size + 1024);
+    let compressed = fs::read(repo.top.join(object_path(zeros.trim()))).unwrap();
+    assert_ne!(compressed[1] >> 6, 0, "{:#x}", compressed[1]);
+    assert!(compressed.len() < size / 100);
+
+    let out = repo.run(&["cat-file", "-p", noise.trim()]);
+    assert!(out.stdout == fs::read(repo.top.join("noise.bin")).unwrap());
+    assert_eq!(
+        repo.ok(&["cat-file", "-s", zeros.trim()]),
+        format!("{}\n", size)
+    );
+}
+
+#[test]
+fn z_ends_entries_with_nul() {
+    let repo = Repo::new("nul");
+    let blob = repo.blob("x");
+    let tree = repo.tree(&[("100644", "a file", &blob), ("100644", "b", &blob)]);
+    let commit = repo.commit(&tree, &[], "first");
+    repo.ok(&["update-ref", "HEAD", &commit]);
+    repo.ok(&["reset", &commit]);
+
+    assert_eq!(
+        repo.ok(&["ls-tree", "--name-only", "-z", &tree]),
+        "a file\0b\0"
+    );
+    let listing = repo.ok(&["ls-tree", "-z", &tree]);
+    let expected = format!("100644 blob {} a file\0100644 blob {} b\0", blob, blob);
+    assert_eq!(listing, expected);
+    assert_eq!(repo.ok(&["ls-files", "-z"]), "a file\0b\0");
+    assert_eq!(repo.ok(&["ls-files"]), "a file\nb\n");
+}
+
+#[test]
+fn progress_goes_to_stderr_and_leaves_stdout_the_sha() {
+    let repo = Repo::new("progress");
+    for name in ["a", "b", "dir/c"] {
+        repo.write(name, name);
+    }
+    let out = repo.run(&["write-tree", "--progress"]);
+    assert!(out.status.success(), "{}", stderr(&out));
+    let sha = String::from_utf8(out.stdout.clone()).unwrap();
+    assert_eq!(sha, repo.ok(&["write-tree"]));
+    assert_eq!(sha.len(), 41);
+    assert!(
+        stderr(&out).ends_with("\rHashing files: 3, done.\n"),
+        "{:?}",
+        stderr(&out)
+    );
+
+    // Without the flag and off a terminal it stays quiet, the flag wins over `-q`
+    let out = repo.run(&["write-tree"]);
+    assert_eq!(stderr(&out), "");
+    let out = repo.run(&["write-tree", "--progress", "-q"]);
+    assert!(stderr(&out).ends_with("3, done.\n"));
+}
+
+#[test]
+fn batch_file_reads_the_shas_from_a_file() {
+    let repo = Repo::new("batch-file");
+    let blob = repo.blob("hello");
+    let missing = "0123456789abcdef0123456789abcdef01234567";
+    repo.write("shas.txt", format!("{}\n{}\n", blob, missing));
+
+    let out = repo.run_with(
+        &["cat-file", "--batch-check", "--batch-file", "shas.txt"],
+        b"ignored\n",
+    );
+    assert!(out.status.success(), "{}", stderr(&out));
+    let expected = format!("{} blob 5\n{} missing\n", blob, missing);
+    assert_eq!(String::from_utf8(out.stdout).unwrap(), expected);
+    let out = repo.run(&["cat-file", "--batch-check", "--batch-file", "nope.txt"]);
+    assert!(!out.status.success());
+}
+
+#[test]
+fn no_gitignore_snapshots_ignored_files_too() {
+    let repo = Repo::new("no-gitignore");
+    repo.write(".gitignore", "*.log\nbuild/\n");
+    repo.write("a", "a");
+    repo.write("x.log", "log");
+    repo.write("build/out", "out");
+
+    let names = |args: &[&str]| {
+        let tree = repo.ok(args);
+        repo.ok(&["ls-tree", "-r", "--name-only", tree.trim()])
+    };
+    assert_eq!(names(&["write-tree"]), ".gitignore\na\n");
+    assert_eq!(
+        names(&["write-tree", "--no-gitignore"]),
+        ".gitignore\na\nbuild/out\nx.log\n"
+    );
+}
+
+#[test]
+fn archive_extracts_to_the_files_of_the_tree() {
+    let repo = Repo::new("archive");
+    let readme = repo.blob("read me\n");
+    let run = repo.blob("#!/bin/sh\necho hi\n");
+    let link = repo.blob("../README");
+    let bin = repo.tree(&[("100755", "run", &run), ("120000", "readme", &link)]);
+    let tree = repo.tree(&[("100644", "README", &readme), ("40000", "bin", &bin)]);
+    let commit = repo.commit(&tree, &[], "first");
+    repo.ok(&["update-ref", "HEAD", &commit]);
+    repo.ok(&["archive", "-o", "out.tar", "--prefix", "proj/", "HEAD"]);
+
+    let dest = repo.top.join("extracted");
+    fs::create_dir_all(&dest).unwrap();
+    let status = Command::new("tar")
+        .arg("-xf")
+        .arg(repo.top.join("out.tar"))
+        .current_dir(&dest)
+        .status()
+        .unwrap();
+    assert!(status.success());
+    let proj = dest.join("proj");
+    assert_eq!(
+        fs::read_to_string(proj.join("README")).unwrap(),
+        "read me\n"
+    );
+    let script = proj.join("bin/run");
+    assert_eq!(fs::read_to_string(&script).unwrap(), "#!/bin/sh\necho hi\n");
+    assert_eq!(
+        fs::metadata(&script).unwrap().permissions().mode() & 0o777,
+        0o755
+    );
+    let readme_link = proj.join("bin/readme");
+    assert_eq!(fs::read_link(&readme_link).unwrap(), Path::new("../README"));
+    assert_eq!(fs::read_to_string(readme_link).unwrap(), "read me\n");
+}
+
+#[test]
+fn check_ignore_exits_by_whether_any_path_is_ignored() {
+    let repo = Repo::new("check-ignore");
+    repo.write(".gitignore", "*.log\n!keep.log\n");
+
+    let out = repo.run(&["check-ignore", "x.log", "a.txt"]);
+    assert_eq!(out.status.code(), Some(0));
+    assert_eq!(out.stdout, b"x.log\n");
+    let out = repo.run(&["check-ignore", "a.txt", "keep.log"]);
+    assert_eq!(out.status.code(), Some(1));
+    assert_eq!(out.stdout, b"");
+    let verbose = repo.ok(&["check-ignore", "-v", "x.log", "keep.log"]);
+    assert_eq!(
+        verbose,
+        ".gitignore:1:*.log\tx.log\n.gitignore:2:!keep.log\tkeep.log\n"
+    );
+    assert_eq!(
+        repo.run(&["check-ignore", "../x.log"]).status.code(),
+        Some(128)
+    );
+}
+
+#[test]
+fn hash_object_stdout_prints_the_compressed_object() {
+    let repo = Repo::new("hash-object-stdout");
+    repo.write("f", "hello world\n");
+    let sha = "3b18e512dba79e4c8300dd08aeb37f8e728b8dad";
+
+    let out = repo.run(&["hash-object", "--stdout", "f"]);
+    assert!(out.status.success(), "{}", stderr(&out));
+    let mut obj = vec![];
+    ZlibDecoder::new(out.stdout.as_slice())
+        .read_to_end(&mut obj)
+        .unwrap();
+    assert_eq!(obj, b"blob 12\0hello world\n");
+    assert!(!repo.exists(&object_path(sha)));
+
+    let written = repo.run(&["hash-object", "--stdout", "-w", "f"]);
+    assert_eq!(written.stdout, out.stdout);
+    let stored = fs::read(repo.top.join(object_path(sha))).unwrap();
+    assert_eq!(stored, out.stdout);
+}
+
+#[test]
+fn abbrev_ref_names_the_branch_or_head_when_detached() {
+    let repo = Repo::new("abbrev-ref");
+    let commit = repo.commit(&repo.tree(&[]), &[], "first");
+    repo.ok(&["update-ref", "HEAD", &commit]);
+    repo.ok(&["update-ref", "refs/heads/topic", &commit]);
+
+    assert_eq!(repo.ok(&["rev-parse", "--abbrev-ref", "HEAD"]), "master\n");
+    assert_eq!(repo.ok(&["rev-parse", "--abbrev-ref", "topic"]), "topic\n");
+    repo.write(".idiot/HEAD", format!("{}\n", commit));
+    assert_eq!(repo.ok(&["rev-parse", "--abbrev-ref", "HEAD"]), "HEAD\n");
+    assert_eq!(repo.ok(&["rev-parse", "HEAD"]), format!("{}\n", commit));
+}
+
+#[test]
+fn report_storage_notes_a_loose_object_on_stderr() {
+    let repo = Repo::new("report-storage");
+    let sha = repo.blob("hello");
+    let on_disk = fs::metadata(repo.top.join(object_path(&sha)))
+        .unwrap()
+        .len();
+
+    let out = repo.run(&["cat-file", "-p", "--report-storage", &sha]);
+    assert!(out.status.success(), "{}", stderr(&out));
+    assert_eq!(out.stdout, b"hello");
+    assert_eq!(
+        stderr(&out),
+        format!("{}: loose, {} bytes on disk\n", sha, on_disk)
+    );
+    assert_eq!(stderr(&repo.run(&["cat-file", "-p", &sha])), "");
+}
+
+#[test]
+fn stdin_paths_hashes_each_path_read() {
+    let repo = Repo::new("stdin-paths");
+    repo.write("a", "hello world\n");
+    repo.write("b c", "");
+    let shas = "3b18e512dba79e4c8300dd08aeb37f8e728b8dad\n\
+                e69de29bb2d1d6434b8b29ae775ad8c2e48c5391\n";
+
+    let out = repo.run_with(&["hash-object", "--stdin-paths"], b"a\nb c\n");
+    assert_eq!(String::from_utf8(out.stdout).unwrap(), shas);
+    let out = repo.run_with(&["hash-object", "--stdin-paths", "-z", "-w"], b"a\0b c\0");
+    assert_eq!(String::from_utf8(out.stdout).unwrap(), shas);
+    assert!(repo.exists(&object_path("3b18e512dba79e4c8300dd08aeb37f8e728b8dad")));
+}
+
+#[test]
+fn write_tree_output_has_no_debug_debris() {
+    let repo = Repo::new("write-tree-debris");
+    repo.write("a", "a\n");
+    repo.write("dir/b", "b\n");
+
+    let out = repo.ok(&["write-tree"]);
+    assert_eq!(out.trim().len(), 40);
+    let verbose = repo.ok(&["write-tree", "-v"]);
+    for output in [&out, &verbose] {
+        assert!(
+            !output.contains("Some(") && !output.contains("None"),
+            "{}",
+            output
+        );
+        assert!(!output.contains('"'), "{}", output);
+    }
+    assert!(
+        verbose.starts_with(&format!("tree 59 (SHA: {})\n", out.trim())),
+        "{}",
+        verbose
+    );
+}
+
+#[test]
+fn recurse_submodules_snapshots_nested_files_instead_of_a_gitlink() {
+    let repo = Repo::new("recurse-submodules");
+    repo.write("a", "a\n");
+    let sub = repo.nested("lib");
+    sub.write("inside", "inside\n");
+    let commit = sub.commit(&sub.tree(&[]), &[], "sub");
+    sub.ok(&["update-ref", "HEAD", &commit]);
+    let names = |args: &[&str]| {
+        let tree = repo.ok(args);
+        repo.ok(&["-q", "ls-tree", "-r", tree.trim()])
+    };
+
+    let gitlink = names(&["write-tree"]);
+    assert!(
+        gitlink.ends_with(&format!("160000 commit {} lib\n", commit)),
+        "{}",
+        gitlink
+    );
+    let recursed = names(&["write-tree", "--recurse-submodules"]);
+    let inside = "5be24b7e8f4ff445fb089b101bb4f0f4909d84d5";
+    assert!(
+        recursed.ends_with(&format!("100644 blob {} lib/inside\n", inside)),
+        "{}",
+        recursed
+    );
+    assert!(
+        !recursed.contains("160000") && !recursed.contains(".idiot"),
+        "{}",
+        recursed
+    );
+}
+
+#[test]
+fn raw_file_reads_an_object_outside_the_store() {
+    let repo = Repo::new("raw-file");
+    let sha = repo.blob("recovered\n");
+    let path = repo.top.join(object_path(&sha));
+    fs::rename(&path, repo.top.join("backup.obj")).unwrap();
+
+    assert!(!repo.run(&["cat-file", "-p", &sha]).status.success());
+    assert_eq!(
+        repo.ok(&["cat-file", "-p", "--raw-file", "backup.obj"]),
+        "recovered\n"
+    );
+    assert_eq!(
+        repo.ok(&["cat-file", "-t", "--raw-file", "backup.obj"]),
+        "blob\n"
+    );
+    assert_eq!(
+        repo.ok(&["cat-file", "-s", "--raw-file", "backup.obj"]),
+        "10\n"
+    );
+    let out = repo.run(&["cat-file", "-p", "--raw-file", "nope.obj"]);
+    assert!(!out.status.success());
+}
+
+#[test]
+fn ignore_case_matches_patterns_whatever_the_case() {
+    let repo = Repo::new("ignore-case");
+    repo.write(".gitignore", "*.txt\n");
+
+    assert_eq!(
+        repo.run(&["check-ignore", "Foo.TXT"]).status.code(),
+        Some(1)
+    );
+    assert_eq!(
+        repo.ok(&["check-ignore", "--ignore-case", "Foo.TXT"]),
+        "Foo.TXT\n"
+    );
+    repo.write(".idiot/config", "[core]\n\tignoreCase = true\n");
+    assert_eq!(repo.ok(&["check-ignore", "Foo.TXT"]), "Foo.TXT\n");
+}
+
+#[test]
+fn update_ref_only_moves_a_ref_from_the_expected_value() {
+    let repo = Repo::new("update-ref-old");
+    let first = repo.commit(&repo.tree(&[]), &[], "first");
+    let second = repo.commit(&repo.tree(&[]), &[&first], "second");
+    let branch = "refs/heads/topic";
+    repo.ok(&["update-ref", branch, &first]);
+
+    let out = repo.run(&["update-ref", branch, &second, &second]);
+    assert!(!out.status.success());
+    assert!(
+        stderr(&out).contains("changed, it is at"),
+        "{}",
+        stderr(&out)
+    );
+    assert_eq!(repo.ok(&["rev-parse", branch]), format!("{}\n", first));
+
+    repo.ok(&["update-ref", branch, &second, &first]);
+    assert_eq!(repo.ok(&["rev-parse", branch]), format!("{}\n", second));
+}
+
+#[test]
+fn follow_symlinks_reads_what_an_in_tree_link_points_at() {
+    let repo = Repo::new("follow-symlinks");
+    let real = repo.blob("real\n");
+    let up = repo.tree(&[("120000", "up", &repo.blob("../target.txt"))]);
+    let tree = repo.tree(&[
+        ("120000", "a", &repo.blob("b")),
+        ("120000", "b", &repo.blob("a")),
+        ("40000", "dir", &up),
+        ("120000", "link", &repo.blob("target.txt")),
+        ("100644", "target.txt", &real),
+    ]);
+    repo.ok(&["update-ref", "HEAD", &repo.commit(&tree, &[], "links")]);
+
+    assert_eq!(repo.ok(&["cat-file", "-p", "HEAD:link"]), "target.txt");
+    let follow = |path: &str| repo.run(&["cat-file", "-p", "--follow-symlinks", path]);
+    assert_eq!(follow("HEAD:link").stdout, b"real\n");
+    assert_eq!(follow("HEAD:dir/up").stdout, b"real\n");
+    assert!(!follow("HEAD:a").status.success());
+}
+
+#[test]
+fn directories_marked_ends_subtree_names_with_a_slash() {
+    let repo = Repo::new("directories-marked");
+    let blob = repo.blob("x");
+    let dir = repo.tree(&[("100644", "inner", &blob)]);
+    let tree = repo.tree(&[
+        ("100644", "a", &blob),
+        ("100644", "dir.txt", &blob),
+        ("40000", "dir", &dir),
+    ]);
+
+    let marked = repo.ok(&["ls-tree", "--name-only", "--directories-marked", &tree]);
+    assert_eq!(marked, "a\ndir/\ndir.txt\n");
+    assert_eq!(
+        repo.ok(&["ls-tree", "--name-only", &tree]),
+        "a\ndir\ndir.txt\n"
+    );
+}
+
+#[test]
+fn trees_only_and_blobs_only_filter_a_recursive_listing() {
+    let repo = Repo::new("trees-blobs-only");
+    let blob = repo.blob("x");
+    let commit = repo.commit(&repo.tree(&[]), &[], "sub");
+    let deeper = repo.tree(&[("100644", "f", &blob)]);
+    let dir = repo.tree(&[("40000", "deeper", &deeper), ("120000", "link", &blob)]);
+    let tree = repo.tree(&[
+        ("100644", "a", &blob),
+        ("40000", "dir", &dir),
+        ("160000", "sub", &commit),
+    ]);
+
+    let names = |filter: &str| repo.ok(&["ls-tree", "-r", "--name-only", filter, &tree]);
+    assert_eq!(names("-d"), "dir\ndir/deeper\n");
+    assert_eq!(names("--trees-only"), "dir\ndir/deeper\n");
+    assert_eq!(names("--blobs-only"), "a\ndir/deeper/f\ndir/link\n");
+    let top = repo.ok(&["ls-tree", "--name-only", "-d", &tree]);
+    assert_eq!(top, "dir\n");
+    let nul = repo.ok(&["ls-tree", "-r", "-z", "--name-only", "-d", &tree]);
+    assert_eq!(nul, "dir\0dir/deeper\0");
+}
+
+#[test]
+fn update_ref_stdin_applies_every_update_or_none() {
+    let repo = Repo::new("update-ref-stdin");
+    let first = repo.commit(&repo.tree(&[]), &[], "first");
+    let second = repo.commit(&repo.tree(&[]), &[&first], "second");
+    repo.ok(&["update-ref", "refs/heads/old", &first]);
+
+    let commands = format!(
+        "update refs/heads/a {}\nupdate refs/heads/old {} {}\n",
+        first, second, first
+    );
+    let out = repo.run_with(&["update-ref", "--stdin"], commands.as_bytes());
+    assert!(out.status.success(), "{}", stderr(&out));
+    assert_eq!(
+        repo.ok(&["rev-parse", "refs/heads/a"]),
+        format!("{}\n", first)
+    );
+    assert_eq!(
+        repo.ok(&["rev-parse", "refs/heads/old"]),
+        format!("{}\n", second)
+    );
+
+    // A stale old value stops the whole batch before anything is written
+    let commands = format!(
+        "update refs/heads/b {}\nupdate refs/heads/old {} {}\n",
+        first, first, first
+    );
+    let out = repo.run_with(&["update-ref", "--stdin"], commands.as_bytes());
+    assert!(!out.status.success());
+    assert!(!repo.run(&["rev-parse", "refs/heads/b"]).status.success());
+    assert_eq!(
+        repo.ok(&["rev-parse", "refs/heads/old"]),
+        format!("{}\n", second)
+    );
+}
+
+#[test]
+fn branch_d_deletes_branches_but_not_the_current_one() {
+    let repo = Repo::new("branch-delete");
+    let first = repo.commit(&repo.tree(&[]), &[], "first");
+    let ahead = repo.commit(&repo.tree(&[]), &[&first], "ahead");
+    repo.ok(&["update-ref", "HEAD", &first]);
+    repo.ok(&["update-ref", "refs/heads/merged", &first]);
+    repo.ok(&["update-ref", "refs/heads/unmerged", &ahead]);
+    repo.ok(&["update-ref", "refs/heads/packed", &first]);
+    repo.ok(&["pack-refs"]);
+
+    repo.ok(&["branch", "-d", "merged", "packed"]);
+    assert_eq!(repo.ok(&["branch"]), "* master\n  unmerged\n");
+    let out = repo.run(&["branch", "-d", "master"]);
+    assert!(!out.status.success());
+    assert!(stderr(&out).contains("HEAD is on it"), "{}", stderr(&out));
+    assert!(!repo.run(&["branch", "-d", "unmerged"]).status.success());
+    repo.ok(&["branch", "-D", "unmerged"]);
+    assert_eq!(repo.ok(&["branch"]), "* master\n");
+
+    repo.ok(&["update-ref", "refs/tags/v1", &first]);
+    repo.ok(&["update-ref", "-d", "refs/tags/v1"]);
+    assert!(!repo.run(&["rev-parse", "refs/tags/v1"]).status.success());
+}
+
+#[test]
+fn remote_add_is_listed_by_remote_v() {
+    let repo = Repo::new("remote-add");
+    repo.ok(&["remote", "add", "origin", "https://example.com/a.git"]);
+    repo.ok(&["remote", "add", "mirror", "/srv/a.git"]);
+    assert_eq!(repo.ok(&["remote"]), "origin\nmirror\n");
+    assert_eq!(
+        repo.ok(&["remote", "-v"]),
+        "origin\thttps://example.com/a.git (fetch)\n\
+         origin\thttps://example.com/a.git (push)\n\
+         mirror\t/srv/a.git (fetch)\n\
+         mirror\t/srv/a.git (push)\n"
+    );
+    let config = fs::read_to_string(repo.top.join(".idiot/config")).unwrap();
+    assert!(
+        config.contains("fetch = +refs/heads/*:refs/remotes/origin/*"),
+        "{}",
+        config
+    );
+
+    let out = repo.run(&["remote", "add", "origin", "https://example.com/b.git"]);
+    assert!(
+        stderr(&out).contains("remote 'origin' already exists"),
+        "{}",
+        stderr(&out)
+    );
+    for (name, url) in [
+        ("bad name", "/srv/b.git"),
+        ("..", "/srv/b.git"),
+        ("other", "gopher://x"),
+    ] {
+        let out = repo.run(&["remote", "add", name, url]);
+        assert!(!out.status.success(), "{} {}", name, url);
+    }
+    assert_eq!(repo.ok(&["remote"]), "origin\nmirror\n");
+}